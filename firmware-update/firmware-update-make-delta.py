@@ -0,0 +1,80 @@
+#!/usr/bin/python3
+# Create a delta image from the root filesystem of the image the devices run (base)
+# and that of the new image (target), both extracted to directories.
+#
+# Regular files that are identical in both, including mode and owner, are listed
+# in the delta manifest and copied from the running bank by firmware-update.
+# Everything else goes into the archive, and so do etc/fstab and the files in
+# firmware-update-filelist.txt, which differ on every device. Compress the
+# resulting .tar with zstd, or give an output name ending in .tar.xz or .tar.gz.
+import argparse
+import hashlib
+import io
+import json
+import os
+import sys
+import tarfile
+
+MANIFEST_PATH = "firmware-update-delta.json"
+VERSION_FILENAME = "image_built_at.txt"
+FSTAB_PATH = "etc/fstab"
+
+def sha256(path):
+    h = hashlib.sha256()
+    with open(path, "rb") as f:
+        for chunk in iter(lambda: f.read(1024 * 1024), b""):
+            h.update(chunk)
+    return h.hexdigest()
+
+def is_unchanged(base_path, target_path):
+    try:
+        b = os.lstat(base_path)
+    except FileNotFoundError:
+        return False
+    t = os.lstat(target_path)
+    return (tarfile.stat.S_ISREG(b.st_mode) and
+            (b.st_mode, b.st_uid, b.st_gid, b.st_size) == (t.st_mode, t.st_uid, t.st_gid, t.st_size) and
+            sha256(base_path) == sha256(target_path))
+
+parser = argparse.ArgumentParser(description="Create a firmware-update delta image")
+parser.add_argument('base', help="Directory with the root filesystem of the image the devices run")
+parser.add_argument('target', help="Directory with the root filesystem of the new image")
+parser.add_argument('output', help="Delta image to write, .tar, .tar.xz or .tar.gz")
+parser.add_argument('--filelist', help="Config files copied between banks",
+        default=os.path.join(os.path.dirname(os.path.abspath(__file__)), "firmware-update-filelist.txt"))
+cli_args = parser.parse_args()
+
+with open(cli_args.filelist) as f:
+    device_specific = {line.strip() for line in f if line.strip()}
+device_specific.add(FSTAB_PATH)
+
+with open(os.path.join(cli_args.base, VERSION_FILENAME)) as f:
+    base_version = f.read().strip()
+
+unchanged = {}
+changed = []
+for dirpath, dirnames, filenames in os.walk(cli_args.target):
+    dirnames.sort()
+    for name in dirnames + sorted(filenames):
+        target_path = os.path.join(dirpath, name)
+        relpath = os.path.relpath(target_path, cli_args.target)
+        if relpath not in device_specific and \
+                not os.path.islink(target_path) and os.path.isfile(target_path) and \
+                is_unchanged(os.path.join(cli_args.base, relpath), target_path):
+            unchanged[relpath] = sha256(target_path)
+        else:
+            changed.append(relpath)
+
+manifest = json.dumps({"base_version": base_version, "unchanged": unchanged}, indent=1).encode()
+
+mode = {".xz": "w:xz", ".gz": "w:gz"}.get(os.path.splitext(cli_args.output)[1], "w")
+with tarfile.open(cli_args.output, mode, format=tarfile.PAX_FORMAT) as tar:
+    info = tarfile.TarInfo(MANIFEST_PATH)
+    info.size = len(manifest)
+    tar.addfile(info, io.BytesIO(manifest))
+
+    for relpath in changed:
+        tar.add(os.path.join(cli_args.target, relpath), arcname=relpath, recursive=False)
+
+print("Delta against {}: {} entries in the archive, {} unchanged files".format(
+    base_version, len(changed), len(unchanged)), file=sys.stderr)
@@ -18,8 +18,13 @@ sock.connect("tcp://127.0.0.1:5552")
 #{'status': 'Ok', 'detail': "some string"}
 # or
 #{'status': 'Error', 'detail': "some string about the error"}
+# When the daemon has tokens configured, commands without a valid token return
+#{'status': 'Unauthorized', 'detail': "some string"}
+# and read_only tokens can only use GetStatus
 
 def send_command(command):
+    if cli_args.token:
+        command["token"] = cli_args.token
     sock.send(json.dumps(command).encode())
 
     socks = dict(poller.poll(8000))
@@ -35,18 +40,47 @@ def send_command(command):
 
 # GetStatus returns the JSON that deserialises to:
 # {'status': 'Status',
-#  'progress': None
+#  'progress': {
+#   'phase': 'Extracting',
+#   'percent': 42,
+#   'bytes_transferred': 215000000,
+#   'total_bytes': 512000000,
+#   'files_extracted': 12000,
+#   'bytes_per_second': 1250000,
+#   'eta_seconds': 237},
+#  'last_update': {
+#   'result': 'Failed',
+#   'error': 'Image SHA-256 mismatch: expected ..., got ...',
+#   'from_url': 'https://example.com/image.tar.zstd',
+#   'started_at': '2024-06-18T08:00:00Z',
+#   'finished_at': '2024-06-18T08:20:00Z',
+#   'bytes_downloaded': 512000000,
+#   'files_extracted': 31337},
 #  'banks': {
 #   'our_bank': 'A',
+#   'our_bank_inconsistencies': [],
 #   'desired_bank': None,
 #   'last_tried_bank': None,
 #   'last_ok_bank': None,
+#   'boot_counter': {'bootcount': 0, 'bootlimit': 3, 'upgrade_available': False},
 #   'our_version': '2024-06-17 13:00:09+00:00',
 #   'our_extract_time': None,
 #   'other_version': '2024-06-17 13:00:09+00:00',
-#   'other_extract_time': None}}
+#   'other_extract_time': None,
+#   'other_invalid_reason': None}}
 #
-# progress is either None (JSON: null) when not updating, or a percentage when an update is ongoing
+# our_bank is detected from the device mounted on /. our_bank_inconsistencies lists disagreements
+# with /proc/cmdline and /etc/fstab. The other bank is not touched while there are any.
+#
+# other_invalid_reason is set when the other bank contains an interrupted update or an image
+# that failed verification. SetDesiredBank refuses to select such a bank.
+# progress is either None (JSON: null) when not updating, or the above when an update is ongoing.
+# phase is one of Connecting, Formatting, Mounting, Extracting, CopyingConfig, Finalising,
+# or for raw ext4 images Connecting, Writing, Resizing, Mounting, CopyingConfig, Finalising.
+# Delta images go through CopyingUnchanged after Extracting.
+# percent, total_bytes and eta_seconds are None if the server did not send a Content-Length.
+# last_update is None until an update has finished, result is one of Success, Failed or Cancelled,
+# and error is set when the update failed
 def do_get_status(cli_args):
     send_command({"command": "GetStatus"})
 
@@ -56,26 +90,62 @@ def do_update(cli_args):
         "command": "Update",
         "from_url": cli_args.url,
         "username": None,
-        "password": None })
+        "password": None,
+        "sha256": cli_args.sha256 })
+
+def do_cancel_update(cli_args):
+    send_command({"command": "CancelUpdate"})
 
 def do_set_desired_bank(cli_args):
     send_command({"command": "SetDesiredBank", "bank": cli_args.bank})
 
+# SetBankOk runs the health checks first. If a required one fails, it returns
+# {'status': 'HealthChecksFailed', 'detail': '...', 'report': {...}}
+# with the report as in RunHealthChecks
 def do_set_bank_ok(cli_args):
     send_command({"command": "SetBankOk"})
 
+# RunHealthChecks returns
+# {'status': 'HealthChecks',
+#  'report': {
+#   'passed': False,
+#   'checks': [
+#    {'check': 'IIO device dexter_dsp_tx exists', 'required': True, 'passed': False,
+#     'error': 'No IIO device named dexter_dsp_tx'}]}}
+def do_run_health_checks(cli_args):
+    send_command({"command": "RunHealthChecks"})
+
+# GetUBootEnv returns
+# {'status': 'UBootEnv', 'variables': {'bootcmd': '...', 'desired_bank': 'A', ...}}
+def do_get_uboot_env(cli_args):
+    send_command({"command": "GetUBootEnv"})
+
+# Variables without a value are unset. desired_bank, last_tried_bank, last_ok_bank,
+# bootcount, bootlimit and upgrade_available cannot be changed this way.
+def do_set_uboot_env(cli_args):
+    variables = {}
+    for v in cli_args.variables:
+        name, sep, value = v.partition("=")
+        variables[name] = value if sep else None
+    send_command({"command": "SetUBootEnv", "variables": variables})
+
 parser = argparse.ArgumentParser(description="FW UPD TOOL remote control")
 parser.set_defaults(func=lambda x: print("specify subcommand!"))
+parser.add_argument('--token', help="Token to send with the command, if the daemon requires one")
 subparsers = parser.add_subparsers(help='Select among the following sub-commands:')
 
 parser_get_status = subparsers.add_parser('get-status', help='Get FW upd bank info and status')
 parser_get_status.set_defaults(func=do_get_status)
 
 parser_update = subparsers.add_parser('update', help='Start a firmware update')
-parser_update.add_argument('-u', '--url', required=True, help="URL from where to download the .tar.zstd")
+parser_update.add_argument('-u', '--url', required=True, help="URL or path of the .tar.zstd, or removable: for the first image on a USB stick")
+parser_update.add_argument('--sha256', help="Expected SHA-256 of the .tar.zstd. Default: taken from <url>.manifest.json if present")
 parser_update.set_defaults(func=do_update)
 # TODO --user and --pass optional arguments
 
+parser_cancel_update = subparsers.add_parser('cancel-update', help='Cancel the ongoing firmware update')
+parser_cancel_update.set_defaults(func=do_cancel_update)
+
 parser_set_desired_bank = subparsers.add_parser('set-desired-bank', help='Set the bank from which to boot')
 parser_set_desired_bank.add_argument('-b', '--bank', required=True, help="Bank. Possible values: A or B")
 parser_set_desired_bank.set_defaults(func=do_set_desired_bank)
@@ -83,5 +153,15 @@ parser_set_desired_bank.set_defaults(func=do_set_desired_bank)
 parser_set_ok_bank = subparsers.add_parser('set-bank-ok', help='Set the current bank as ok in the last_bank_ok variable')
 parser_set_ok_bank.set_defaults(func=do_set_bank_ok)
 
+parser_run_health_checks = subparsers.add_parser('run-health-checks', help='Run the health checks of the current bank')
+parser_run_health_checks.set_defaults(func=do_run_health_checks)
+
+parser_get_uboot_env = subparsers.add_parser('get-uboot-env', help='Show the U-Boot environment')
+parser_get_uboot_env.set_defaults(func=do_get_uboot_env)
+
+parser_set_uboot_env = subparsers.add_parser('set-uboot-env', help='Change U-Boot environment variables')
+parser_set_uboot_env.add_argument('variables', nargs='+', help="name=value to set, or name to unset")
+parser_set_uboot_env.set_defaults(func=do_set_uboot_env)
+
 cli_args = parser.parse_args()
 cli_args.func(cli_args)
@@ -0,0 +1,95 @@
+//! Authorisation of the commands received on the control socket and the HTTP API.
+//!
+//! When tokens are configured, every request must carry one of them in its "token" field
+//! (or in an `Authorization: Bearer` header for HTTP). The role of the token decides which
+//! commands it may run. Without configured tokens, every client may run every command.
+
+use serde::Deserialize;
+
+use crate::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// May only call GetStatus
+    ReadOnly,
+    /// May call every command
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    pub token: String,
+    pub role: Role,
+}
+
+impl Role {
+    fn allows(&self, command: &Command) -> bool {
+        match self {
+            Role::ReadOnly => matches!(command, Command::GetStatus),
+            Role::Admin => true,
+        }
+    }
+}
+
+/// Check that the token given with the request allows the command
+pub fn authorize(tokens: &[Token], token: Option<&str>, command: &Command) -> Result<(), String> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    let given = token.ok_or("A token is required")?;
+    let role = tokens.iter()
+        .find(|t| constant_time_eq(t.token.as_bytes(), given.as_bytes()))
+        .map(|t| t.role)
+        .ok_or("Invalid token")?;
+
+    if role.allows(command) {
+        Ok(())
+    }
+    else {
+        Err("This token is read-only, it may only run GetStatus".to_owned())
+    }
+}
+
+/// Compare without returning early on the first difference, so that the time taken
+/// does not tell how much of a guessed token was correct
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> Vec<Token> {
+        vec![
+            Token { token: "admin-token".to_owned(), role: Role::Admin },
+            Token { token: "status-token".to_owned(), role: Role::ReadOnly },
+        ]
+    }
+
+    #[test]
+    fn no_tokens_configured() {
+        assert!(authorize(&[], None, &Command::FormatOtherBank).is_ok());
+        assert!(authorize(&[], Some("anything"), &Command::FormatOtherBank).is_ok());
+    }
+
+    #[test]
+    fn missing_or_invalid_token() {
+        assert_eq!(authorize(&tokens(), None, &Command::GetStatus).unwrap_err(), "A token is required");
+        assert_eq!(authorize(&tokens(), Some("admin-toke"), &Command::GetStatus).unwrap_err(), "Invalid token");
+        assert_eq!(authorize(&tokens(), Some(""), &Command::GetStatus).unwrap_err(), "Invalid token");
+    }
+
+    #[test]
+    fn read_only_and_admin() {
+        assert!(authorize(&tokens(), Some("status-token"), &Command::GetStatus).is_ok());
+        assert!(authorize(&tokens(), Some("status-token"), &Command::CancelUpdate).is_err());
+        assert!(authorize(&tokens(), Some("status-token"), &Command::SetBankOk).is_err());
+
+        assert!(authorize(&tokens(), Some("admin-token"), &Command::GetStatus).is_ok());
+        assert!(authorize(&tokens(), Some("admin-token"), &Command::FormatOtherBank).is_ok());
+    }
+}
@@ -1,12 +1,19 @@
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
 use serde::{Serialize, Deserialize};
-use regex::Regex;
-use sys_mount::{Mount, Unmount, UnmountDrop, UnmountFlags};
+use sys_mount::{Mount, MountFlags, Unmount, UnmountDrop, UnmountFlags};
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+use crate::config::PartitionLayout;
+use crate::safety;
+
+const OTHER_BANK_MOUNTPOINT : &str = "/mnt/other_bank";
+const CONFIG_FILELIST : &str = "firmware-update-filelist.txt";
+/// Relative to the root of a bank
+pub const FSTAB_PATH : &str = "etc/fstab";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Bank { A, B }
 
 impl Bank {
@@ -17,14 +24,6 @@ impl Bank {
             Bank::B => Bank::A,
         }
     }
-
-    pub fn device(&self) -> &'static str
-    {
-        match self {
-            Bank::A => "/dev/mmcblk0p2",
-            Bank::B => "/dev/mmcblk0p3",
-        }
-    }
 }
 
 impl std::fmt::Display for Bank {
@@ -47,11 +46,75 @@ impl From<&str> for Bank {
 }
 
 
-pub fn detect() -> Result<Bank, Box<dyn std::error::Error>> {
-    let pattern = Regex::new(r"/dev/mmcblk0p([23])[ ]+/[ ]+ext4").unwrap();
+/// The bank we are running from, and the disagreements between the sources used to find it
+pub struct Detection {
+    pub bank : Bank,
+    pub inconsistencies : Vec<String>,
+}
+
+/// Detect the bank we are running from, refusing to answer if the sources disagree.
+pub fn detect(layout: &PartitionLayout) -> Result<Bank, Box<dyn std::error::Error>> {
+    let detection = detect_sources(layout)?;
+    if detection.inconsistencies.is_empty() {
+        Ok(detection.bank)
+    }
+    else {
+        Err(format!("Inconsistent bank detection: {}", detection.inconsistencies.join("; ")).into())
+    }
+}
+
+/// Detect the bank from what the kernel has mounted as root, and compare it with the root=
+/// on the kernel command line and the root entry in /etc/fstab.
+pub fn detect_sources(layout: &PartitionLayout) -> Result<Detection, Box<dyn std::error::Error>> {
+    let mut sources = Vec::new();
+
+    for (source, detected) in [
+        ("/proc/self/mountinfo", bank_from_mountinfo(layout)),
+        ("/proc/cmdline", bank_from_cmdline(layout)),
+        ("/etc/fstab", bank_from_fstab(layout)),
+    ] {
+        match detected {
+            Ok(bank) => sources.push((source, bank)),
+            Err(e) => eprintln!("Cannot detect bank from {}: {}", source, e),
+        }
+    }
+
+    let (first_source, bank) = *sources.first().ok_or("Could not identify bank")?;
+
+    let inconsistencies = sources[1..].iter()
+        .filter(|(_, b)| *b != bank)
+        .map(|(source, b)| format!("{} says bank {} but {} says bank {}", first_source, bank, source, b))
+        .collect();
+
+    Ok(Detection { bank, inconsistencies })
+}
+
+fn bank_from_mountinfo(layout: &PartitionLayout) -> Result<Bank, Box<dyn std::error::Error>> {
+    let mounts = safety::mountinfo()?;
+    let root_device_number = &safety::root_mount(&mounts).ok_or("no root mount")?.device_number;
+
+    for bank in [Bank::A, Bank::B] {
+        if safety::block_device_number(&layout.bank(bank).device)? == *root_device_number {
+            return Ok(bank);
+        }
+    }
+
+    Err(format!("Root device {} is not a bank", root_device_number).into())
+}
+
+fn bank_from_cmdline(layout: &PartitionLayout) -> Result<Bank, Box<dyn std::error::Error>> {
+    let cmdline = std::fs::read_to_string("/proc/cmdline")?;
+    let root = cmdline.split_whitespace()
+        .find_map(|arg| arg.strip_prefix("root="))
+        .ok_or("no root= argument")?;
+
+    bank_from_device_spec(layout, root)
+}
 
+fn bank_from_fstab(layout: &PartitionLayout) -> Result<Bank, Box<dyn std::error::Error>> {
     // Read /etc/fstab,
-    // grep for line `/dev/mmcblk0p[23] / ext4 defaults,noatime 0 1`
+    // look for the line that mounts / e.g.
+    // `/dev/mmcblk0p2 / ext4 defaults,noatime 0 1`
     // and see on which partition this is
 
     let file = File::open("/etc/fstab")?;
@@ -59,61 +122,84 @@ pub fn detect() -> Result<Bank, Box<dyn std::error::Error>> {
 
     for line in reader.lines() {
         let line = line?;
-        if let Some(group) = pattern.captures(&line) {
-            let part_number = u8::from_str_radix(&group[1], 10)?;
-            return match part_number {
-                2 => Ok(Bank::A),
-                3 => Ok(Bank::B),
-                _ => Err(format!("Partition num {} is invalid", part_number).into()),
-            }
+        let fields : Vec<&str> = line.split_whitespace().collect();
+        if fields.len() >= 2 && !fields[0].starts_with('#') && fields[1] == "/" {
+            return bank_from_device_spec(layout, fields[0]);
         }
     }
 
-    Err("Could not identify bank".to_owned().into())
+    Err("no root entry".into())
+}
+
+/// Match a device path or LABEL= against the banks
+fn bank_from_device_spec(layout: &PartitionLayout, spec: &str) -> Result<Bank, Box<dyn std::error::Error>> {
+    for bank in [Bank::A, Bank::B] {
+        let partition = layout.bank(bank);
+        if spec == partition.device || spec == format!("LABEL={}", partition.label) {
+            return Ok(bank);
+        }
+    }
+
+    Err(format!("Root device {} is not a bank", spec).into())
 }
 
 /// Format the other bank as ext4
-pub fn format_other_bank() -> Result<(), Box<dyn std::error::Error>> {
-    let other_bank = detect()?
+pub fn format_other_bank(layout: &PartitionLayout) -> Result<(), Box<dyn std::error::Error>> {
+    let other_bank = detect(layout)?
         .other();
+    let partition = layout.bank(other_bank);
+    safety::check_safe_to_write(&partition.device)?;
 
-    eprintln!("Formatting {} as ext4", other_bank.device());
+    eprintln!("Formatting {} as ext4", partition.device);
 
     let output = std::process::Command::new("mkfs.ext4")
         .arg("-L")
-        .arg(match other_bank {
-            Bank::A => "bank_a",
-            Bank::B => "bank_b",
-        })
-        .arg(other_bank.device())
-        .output()?;
+        .arg(&partition.label)
+        .arg(&partition.device)
+        .output()
+        .map_err(|e| format!("Cannot run mkfs.ext4: {}", e))?;
 
     eprintln!("mkfs.ext4: {}", String::from_utf8_lossy(&output.stdout));
+    if !output.status.success() {
+        return Err(format!("mkfs.ext4 failed with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
     Ok(())
 }
 
+/// Size in bytes of the partition of the other bank
+pub fn other_bank_size(layout: &PartitionLayout) -> Result<u64, Box<dyn std::error::Error>> {
+    let partition = layout.bank(detect(layout)?.other());
+    let mut device = File::open(&partition.device)
+        .map_err(|e| format!("Cannot open {}: {}", partition.device, e))?;
+    Ok(device.seek(SeekFrom::End(0))?)
+}
+
 pub struct MountGuard {
     pub other_bank : Bank,
     pub guard : UnmountDrop<Mount>
 }
 
 /// Mount the other bank and return a guard that will unmount on drop.
-pub fn mount_other_bank() -> Result<MountGuard, Box<dyn std::error::Error>> {
-    let other_bank = detect()?
+pub fn mount_other_bank(layout: &PartitionLayout) -> Result<MountGuard, Box<dyn std::error::Error>> {
+    let other_bank = detect(layout)?
         .other();
+    let partition = layout.bank(other_bank);
+    safety::check_not_root(&partition.device)?;
 
-    let other_bank_mountpoint = "/mnt/other_bank";
-
-    if !Path::new(other_bank_mountpoint).is_dir() {
-        if let Err(e) = std::fs::create_dir(other_bank_mountpoint) {
-            eprintln!("Cannot create dir {}: {}", other_bank_mountpoint, e);
+    if !Path::new(OTHER_BANK_MOUNTPOINT).is_dir() {
+        if let Err(e) = std::fs::create_dir(OTHER_BANK_MOUNTPOINT) {
+            eprintln!("Cannot create dir {}: {}", OTHER_BANK_MOUNTPOINT, e);
         }
-        eprintln!("Created {}", other_bank_mountpoint);
+        eprintln!("Created {}", OTHER_BANK_MOUNTPOINT);
     }
 
+    let (flags, data) = mount_flags(&partition.mount_options);
+
     let mount_guard = Mount::builder()
         .fstype("ext4")
-        .mount(other_bank.device(), other_bank_mountpoint)?;
+        .flags(flags)
+        .data(&data)
+        .mount(&partition.device, OTHER_BANK_MOUNTPOINT)?;
 
     Ok(MountGuard{
         other_bank,
@@ -121,24 +207,49 @@ pub fn mount_other_bank() -> Result<MountGuard, Box<dyn std::error::Error>> {
     })
 }
 
-pub fn render_fstab(bank: Bank, fstab_location: &Path) -> Result<(), Box<dyn std::error::Error>> {
+/// Split fstab-style mount options into the flags for mount(2) and the
+/// filesystem-specific options that are passed as data.
+fn mount_flags(options: &str) -> (MountFlags, String) {
+    let mut flags = MountFlags::empty();
+    let mut data = Vec::new();
+
+    for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        match option {
+            "defaults" | "rw" | "auto" | "noauto" => (),
+            "ro" => flags |= MountFlags::RDONLY,
+            "noatime" => flags |= MountFlags::NOATIME,
+            "nodiratime" => flags |= MountFlags::NODIRATIME,
+            "relatime" => flags |= MountFlags::RELATIME,
+            "strictatime" => flags |= MountFlags::STRICTATIME,
+            "nodev" => flags |= MountFlags::NODEV,
+            "noexec" => flags |= MountFlags::NOEXEC,
+            "nosuid" => flags |= MountFlags::NOSUID,
+            "sync" => flags |= MountFlags::SYNCHRONOUS,
+            "dirsync" => flags |= MountFlags::DIRSYNC,
+            _ => data.push(option),
+        }
+    }
+
+    (flags, data.join(","))
+}
+
+pub fn render_fstab(layout: &PartitionLayout, bank: Bank, fstab_location: &Path) -> Result<(), Box<dyn std::error::Error>> {
     eprintln!("Regenerate fstab");
-    let template_a = concat!(
-        "proc            /proc           proc    defaults          0       0\n",
-        "/dev/mmcblk0p1  /boot           vfat    defaults          0       2\n",
-        "/dev/mmcblk0p3  /mnt/other_bank ext4    noauto,noatime    0       0\n",
-        "/dev/mmcblk0p2  /               ext4    defaults,noatime  0       1\n");
-
-    let template_b = concat!(
-        "proc            /proc           proc    defaults          0       0\n",
-        "/dev/mmcblk0p1  /boot           vfat    defaults          0       2\n",
-        "/dev/mmcblk0p2  /mnt/other_bank ext4    noauto,noatime    0       0\n",
-        "/dev/mmcblk0p3  /               ext4    defaults,noatime  0       1\n");
-
-    let new_fstab = match bank {
-        Bank::A => template_a,
-        Bank::B => template_b,
-    };
+
+    let root = layout.bank(bank);
+    let other = layout.bank(bank.other());
+
+    let entries = [
+        ("proc", "/proc", "proc", "defaults".to_owned(), 0),
+        (layout.boot.device.as_str(), layout.boot.mountpoint.as_str(), layout.boot.fstype.as_str(), layout.boot.mount_options.clone(), 2),
+        (other.device.as_str(), OTHER_BANK_MOUNTPOINT, "ext4", format!("noauto,{}", other.mount_options), 0),
+        (root.device.as_str(), "/", "ext4", format!("defaults,{}", root.mount_options), 1),
+    ];
+
+    let mut new_fstab = String::new();
+    for (device, mountpoint, fstype, options, pass) in entries {
+        new_fstab += &format!("{:<15} {:<15} {:<7} {:<17} 0       {}\n", device, mountpoint, fstype, options, pass);
+    }
 
     let mut file = File::options()
         .write(true)
@@ -148,13 +259,26 @@ pub fn render_fstab(bank: Bank, fstab_location: &Path) -> Result<(), Box<dyn std
     Ok(())
 }
 
+/// Files that differ between devices, copied from the running bank by copy_config
+pub fn config_files() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let file = File::open(CONFIG_FILELIST)
+        .map_err(|e| format!("Cannot open {}: {}", CONFIG_FILELIST, e))?;
+    let reader = BufReader::new(file);
+    Ok(reader.lines().collect::<Result<_, _>>()?)
+}
+
+/// Files that are replaced once the image is installed, by copy_config and render_fstab.
+/// Their content in the image does not matter.
+pub fn rewritten_files() -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files : Vec<PathBuf> = config_files()?.into_iter().map(PathBuf::from).collect();
+    files.push(PathBuf::from(FSTAB_PATH));
+    Ok(files)
+}
+
 pub fn copy_config(other_bank_root: &Path) -> Result<(), Box<dyn std::error::Error>> {
     eprintln!("Copy config");
 
-    let file = File::open("firmware-update-filelist.txt")?;
-    let reader = BufReader::new(file);
-    for line in reader.lines() {
-        let filename = line?;
+    for filename in config_files()? {
         let from = PathBuf::from("/").join(&filename);
         let to = other_bank_root.join(&filename);
         if from != to {
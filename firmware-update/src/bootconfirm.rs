@@ -0,0 +1,77 @@
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::banks::Bank;
+use crate::config::BootConfirmation;
+use crate::events::{Event, Publisher};
+use crate::health::{self, HealthCheck};
+use crate::ubootenv;
+
+/// Start the boot confirmation if we booted a bank that was not confirmed yet.
+///
+/// The health checks are retried until they pass or the timeout expires. If they pass,
+/// our bank becomes the last OK bank. If not, we go back to the last OK bank and reboot.
+pub fn spawn_if_needed(settings: &BootConfirmation, checks: &[HealthCheck], our_bank: Bank, last_ok_bank: Option<Bank>, publisher: &Publisher) -> Option<JoinHandle<()>> {
+    if !settings.enabled || last_ok_bank == Some(our_bank) {
+        return None;
+    }
+
+    match last_ok_bank {
+        Some(b) => eprintln!("Booted bank {} but last OK bank is {}, confirming boot", our_bank, b),
+        None => eprintln!("Booted bank {} but no last OK bank is set, confirming boot", our_bank),
+    }
+
+    let settings = settings.clone();
+    let checks = checks.to_vec();
+    let publisher = publisher.clone();
+    Some(spawn(move || confirm_boot(&settings, &checks, our_bank, last_ok_bank, &publisher)))
+}
+
+fn confirm_boot(settings: &BootConfirmation, checks: &[HealthCheck], our_bank: Bank, last_ok_bank: Option<Bank>, publisher: &Publisher) {
+    let deadline = Instant::now() + Duration::from_secs(settings.timeout_seconds);
+
+    loop {
+        let report = health::run_checks(checks);
+        if report.passed {
+            eprintln!("Health checks passed, marking bank {} as OK", our_bank);
+            match ubootenv::mark_bank_ok(our_bank) {
+                Ok(variables) => publisher.publish(Event::UBootEnvChanged{ reason: "Boot confirmed".to_owned(), variables }),
+                Err(e) => eprintln!("Could not mark bank {} as OK: {}", our_bank, e),
+            }
+            return;
+        }
+        else if Instant::now() < deadline {
+            eprintln!("Health checks failed, retrying: {}", report.failures());
+            sleep(Duration::from_secs(settings.retry_interval_seconds));
+        }
+        else {
+            eprintln!("Health checks failed after {} s: {}", settings.timeout_seconds, report.failures());
+            break;
+        }
+    }
+
+    let previous_bank = match last_ok_bank {
+        Some(b) => b,
+        None => {
+            // Without a bank known to work, rebooting into the other one could be worse
+            eprintln!("No last OK bank to roll back to, staying on bank {}", our_bank);
+            return;
+        },
+    };
+
+    eprintln!("Rolling back to bank {}", previous_bank);
+    // The previous bank is known to work, so treat it like our own and do not arm the boot counter
+    match ubootenv::set_desired_bank(previous_bank, previous_bank, None) {
+        Ok(variables) => publisher.publish(Event::UBootEnvChanged{ reason: "Boot confirmation rollback".to_owned(), variables }),
+        Err(e) => {
+            eprintln!("Could not set desired bank {}: {}", previous_bank, e);
+            return;
+        },
+    }
+
+    match std::process::Command::new("reboot").status() {
+        Ok(s) if s.success() => (),
+        Ok(s) => eprintln!("reboot failed with {}", s),
+        Err(e) => eprintln!("Cannot run reboot: {}", e),
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+
+use crate::banks::Bank;
+use crate::config::CONFIG_PATH;
+use crate::control;
+use crate::Command;
+
+/// How long the client waits for the daemon to answer. Some commands, like FormatOtherBank
+/// or RunHealthChecks, take a while, so this is the same as COMMAND_TIMEOUT in http.rs.
+const CLIENT_TIMEOUT : Duration = Duration::from_secs(120);
+
+/// Firmware update with bank A/B support
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration file
+    #[arg(long, global = true, default_value = CONFIG_PATH)]
+    pub config: PathBuf,
+
+    /// ZMQ endpoint of the daemon, e.g. tcp://0.0.0.0:5552 or ipc:///run/firmware-update.sock.
+    /// Default: from the configuration
+    #[arg(long, global = true)]
+    pub endpoint: Option<String>,
+
+    /// Token sent with the command, when the daemon requires one
+    #[arg(long, global = true)]
+    pub token: Option<String>,
+
+    /// Without a subcommand, run the daemon
+    #[command(subcommand)]
+    pub action: Option<Action>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// Run the daemon that executes the commands sent over ZMQ
+    Daemon,
+
+    /// Get bank info, update progress and the outcome of the last update
+    Status,
+
+    /// Print the events published by the daemon, until interrupted
+    Watch,
+
+    /// Start a firmware update
+    Update {
+        /// URL or path of the .tar.zstd, or `removable:` for the first image on a USB stick
+        #[arg(short, long)]
+        url: String,
+
+        /// Username for HTTP Basic Auth
+        #[arg(long, requires = "password")]
+        user: Option<String>,
+
+        /// Password for HTTP Basic Auth
+        #[arg(long, requires = "user")]
+        password: Option<String>,
+
+        /// Expected SHA-256 of the .tar.zstd. Default: taken from <url>.manifest.json if present
+        #[arg(long)]
+        sha256: Option<String>,
+    },
+
+    /// Cancel the ongoing firmware update
+    CancelUpdate,
+
+    /// Format the other bank
+    FormatOtherBank,
+
+    /// Copy config from the current bank to the other bank
+    CopyConfig,
+
+    /// Set the bank from which to boot
+    SetDesiredBank {
+        /// A or B
+        #[arg(short, long, value_parser = parse_bank)]
+        bank: Bank,
+    },
+
+    /// Set the current bank as ok in the last_ok_bank variable, if the health checks pass
+    SetBankOk,
+
+    /// Run the health checks of the current bank
+    RunHealthChecks,
+
+    /// Show the U-Boot environment
+    GetUbootEnv,
+
+    /// Change U-Boot environment variables
+    SetUbootEnv {
+        /// name=value to set, or name to unset
+        #[arg(required = true)]
+        variables: Vec<String>,
+    },
+}
+
+fn parse_bank(s: &str) -> Result<Bank, String> {
+    match s {
+        "A" | "a" => Ok(Bank::A),
+        "B" | "b" => Ok(Bank::B),
+        _ => Err("Valid banks: A or B".to_owned()),
+    }
+}
+
+impl Action {
+    /// The command to send to the daemon, or None for the actions that are not commands
+    pub fn into_command(self) -> Option<Command> {
+        match self {
+            Action::Daemon | Action::Watch => None,
+            Action::Status => Some(Command::GetStatus),
+            Action::Update { url, user, password, sha256 } => Some(Command::Update {
+                from_url: url,
+                username: user,
+                password,
+                sha256,
+            }),
+            Action::CancelUpdate => Some(Command::CancelUpdate),
+            Action::FormatOtherBank => Some(Command::FormatOtherBank),
+            Action::CopyConfig => Some(Command::CopyConfig),
+            Action::SetDesiredBank { bank } => Some(Command::SetDesiredBank { bank }),
+            Action::SetBankOk => Some(Command::SetBankOk),
+            Action::RunHealthChecks => Some(Command::RunHealthChecks),
+            Action::GetUbootEnv => Some(Command::GetUBootEnv),
+            Action::SetUbootEnv { variables } => {
+                let variables : BTreeMap<String, Option<String>> = variables.iter()
+                    .map(|v| match v.split_once('=') {
+                        Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                        None => (v.to_owned(), None),
+                    })
+                    .collect();
+                Some(Command::SetUBootEnv { variables })
+            },
+        }
+    }
+}
+
+/// Send the command to the running daemon and print its answer.
+/// Returns false if the daemon reports an error.
+pub fn send_to_daemon(endpoint: &str, token: Option<&str>, command: Command) -> Result<bool, Box<dyn std::error::Error>> {
+    let ctx = zmq::Context::new();
+    let reply = control::request(&ctx, endpoint, token, command, CLIENT_TIMEOUT)?;
+
+    let reply : serde_json::Value = serde_json::from_str(&reply)?;
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+
+    Ok(!control::is_failure(&reply))
+}
+
+/// Subscribe to all events and print them, one JSON object per line
+pub fn watch(endpoint: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let ctx = zmq::Context::new();
+    let socket = ctx.socket(zmq::SUB)?;
+    socket.connect(endpoint)?;
+    socket.set_subscribe(b"")?;
+
+    loop {
+        let frames = socket.recv_multipart(0)?;
+        match frames.last().map(|f| std::str::from_utf8(f)) {
+            Some(Ok(event)) => println!("{}", event),
+            _ => eprintln!("Ignoring invalid event"),
+        }
+    }
+}
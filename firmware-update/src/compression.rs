@@ -0,0 +1,119 @@
+//! Detection of the compression of the image, so that .tar.zst, .tar.xz, .tar.gz and plain .tar
+//! images all feed the same extraction. The same goes for raw filesystem images, see rawimage.rs.
+//!
+//! The magic bytes at the start of the stream decide. The name of the image is only used for
+//! old-style tar archives, which have no magic.
+
+use std::io::{Cursor, Read};
+
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+use crate::rawimage;
+
+const ZSTD_MAGIC : &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const XZ_MAGIC : &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const GZIP_MAGIC : &[u8] = &[0x1F, 0x8B];
+/// POSIX tar archives have "ustar" at this offset of the first header
+const TAR_MAGIC_OFFSET : usize = 257;
+const TAR_MAGIC : &[u8] = b"ustar";
+/// Enough for the tar header and the ext4 superblock magic
+const HEADER_SIZE : u64 = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Xz,
+    Gzip,
+    None,
+}
+
+pub fn detect(head: &[u8], name: &str) -> Result<Compression, Box<dyn std::error::Error>> {
+    if head.starts_with(ZSTD_MAGIC) {
+        Ok(Compression::Zstd)
+    }
+    else if head.starts_with(XZ_MAGIC) {
+        Ok(Compression::Xz)
+    }
+    else if head.starts_with(GZIP_MAGIC) {
+        Ok(Compression::Gzip)
+    }
+    else if head.get(TAR_MAGIC_OFFSET..).is_some_and(|h| h.starts_with(TAR_MAGIC))
+        || name.ends_with(".tar")
+        || rawimage::is_ext4(head) {
+        Ok(Compression::None)
+    }
+    else {
+        Err(format!("Unknown image format of {}, expected a tar archive or ext4 image, uncompressed or compressed with zstd, xz or gzip", name).into())
+    }
+}
+
+/// Look at the start of the stream and return the matching decoder, which yields the tar archive
+/// or filesystem image.
+/// `name` is the URL or path of the image.
+pub fn decoder<'a>(mut reader: impl Read + 'a, name: &str) -> Result<Box<dyn Read + 'a>, Box<dyn std::error::Error>> {
+    let mut head = Vec::new();
+    reader.by_ref().take(HEADER_SIZE).read_to_end(&mut head)?;
+
+    let compression = detect(&head, name)?;
+    eprintln!("Image compression: {:?}", compression);
+
+    // Put the bytes we looked at back in front of the stream
+    let stream = Cursor::new(head).chain(reader);
+
+    Ok(match compression {
+        Compression::Zstd => Box::new(zstd::stream::Decoder::new(stream)?),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(stream)),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(stream)),
+        Compression::None => Box::new(stream),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn magic_bytes() {
+        assert_eq!(detect(&[0x28, 0xB5, 0x2F, 0xFD, 0x00], "image").unwrap(), Compression::Zstd);
+        assert_eq!(detect(&[0xFD, b'7', b'z', b'X', b'Z', 0x00, 0x00], "image").unwrap(), Compression::Xz);
+        assert_eq!(detect(&[0x1F, 0x8B, 0x08], "image").unwrap(), Compression::Gzip);
+
+        // The magic wins over the name
+        assert_eq!(detect(&[0x1F, 0x8B, 0x08], "image.tar.zst").unwrap(), Compression::Gzip);
+    }
+
+    #[test]
+    fn uncompressed() {
+        let mut tar = vec![0; 512];
+        tar[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5].copy_from_slice(TAR_MAGIC);
+        assert_eq!(detect(&tar, "image").unwrap(), Compression::None);
+
+        // Old-style tar archives are only recognised by their name
+        assert_eq!(detect(&[0; 512], "image.tar").unwrap(), Compression::None);
+
+        let mut ext4 = vec![0; 2048];
+        ext4[1024 + 0x38..1024 + 0x3A].copy_from_slice(&[0x53, 0xEF]);
+        assert_eq!(detect(&ext4, "image.img").unwrap(), Compression::None);
+    }
+
+    #[test]
+    fn unknown() {
+        assert!(detect(&[0; 512], "image.img").is_err());
+        assert!(detect(&[], "image").is_err());
+        assert!(detect(&[0x28, 0xB5], "image").is_err());
+    }
+
+    #[test]
+    fn decoder_keeps_the_head() {
+        let contents : Vec<u8> = (0..10000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&contents).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoded = Vec::new();
+        decoder(&compressed[..], "image").unwrap().read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, contents);
+    }
+}
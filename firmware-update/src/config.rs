@@ -0,0 +1,222 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::auth::Token;
+use crate::banks::Bank;
+use crate::control::{DEFAULT_ENDPOINT, DEFAULT_EVENTS_ENDPOINT};
+use crate::health::HealthCheck;
+
+/// Location of the configuration file. If it does not exist, the defaults below
+/// describe the SD card layout of the Dexter images.
+pub const CONFIG_PATH : &str = "/etc/firmware-update/config.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub control: Control,
+    pub device: Device,
+    pub http: Http,
+    pub download: Download,
+    pub signature: SignatureCheck,
+    pub partitions: PartitionLayout,
+    pub boot_confirmation: BootConfirmation,
+    pub boot_counter: BootCounter,
+    pub health_checks: Vec<HealthCheck>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            eprintln!("No configuration in {}, using defaults", path.to_string_lossy());
+            return Ok(Config::default());
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        match toml::from_str(&contents) {
+            Ok(config) => Ok(config),
+            Err(e) => Err(format!("Invalid configuration {}: {}", path.to_string_lossy(), e).into()),
+        }
+    }
+}
+
+/// Where the daemon receives commands, see control.rs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Control {
+    pub endpoint: String,
+    /// PUB socket for the events in events.rs. None disables the events.
+    pub events_endpoint: Option<String>,
+    /// Permissions of the socket files, for ipc:// endpoints. If not set, the umask applies.
+    pub ipc_mode: Option<u32>,
+    /// If not empty, commands are only accepted with one of these tokens, see auth.rs
+    pub tokens: Vec<Token>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Control {
+            endpoint: DEFAULT_ENDPOINT.to_owned(),
+            events_endpoint: Some(DEFAULT_EVENTS_ENDPOINT.to_owned()),
+            ipc_mode: None,
+            tokens: Vec::new(),
+        }
+    }
+}
+
+/// What the image manifest is checked against, see manifest.rs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Device {
+    pub hardware: Option<String>,
+    pub board_revision: Option<String>,
+    /// If not set, taken from the `ver` variable of the U-Boot env
+    pub bootloader_version: Option<String>,
+}
+
+/// Optional REST API, see http.rs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub enabled: bool,
+    /// Address and port to listen on
+    pub listen: String,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_owned(),
+        }
+    }
+}
+
+/// Retries of the image download, see download.rs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Download {
+    pub connect_timeout_seconds: u64,
+    /// A connection that received nothing for this long is considered broken
+    pub read_timeout_seconds: u64,
+    /// How often the download may be retried or resumed in total
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub initial_backoff_seconds: u64,
+    pub max_backoff_seconds: u64,
+}
+
+impl Default for Download {
+    fn default() -> Self {
+        Download {
+            connect_timeout_seconds: 30,
+            read_timeout_seconds: 60,
+            max_retries: 10,
+            initial_backoff_seconds: 2,
+            max_backoff_seconds: 120,
+        }
+    }
+}
+
+/// Verification of the image signature, see signature.rs
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignatureCheck {
+    /// Install images without verifying them when no public key is installed.
+    /// Otherwise Update fails without a key.
+    pub allow_unsigned: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartitionLayout {
+    pub boot: BootPartition,
+    pub bank_a: BankPartition,
+    pub bank_b: BankPartition,
+}
+
+impl PartitionLayout {
+    pub fn bank(&self, bank: Bank) -> &BankPartition {
+        match bank {
+            Bank::A => &self.bank_a,
+            Bank::B => &self.bank_b,
+        }
+    }
+}
+
+impl Default for PartitionLayout {
+    fn default() -> Self {
+        PartitionLayout {
+            boot: BootPartition::default(),
+            bank_a: BankPartition {
+                device: "/dev/mmcblk0p2".to_owned(),
+                label: "bank_a".to_owned(),
+                mount_options: "noatime".to_owned(),
+            },
+            bank_b: BankPartition {
+                device: "/dev/mmcblk0p3".to_owned(),
+                label: "bank_b".to_owned(),
+                mount_options: "noatime".to_owned(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootPartition {
+    pub device: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    pub mount_options: String,
+}
+
+impl Default for BootPartition {
+    fn default() -> Self {
+        BootPartition {
+            device: "/dev/mmcblk0p1".to_owned(),
+            mountpoint: "/boot".to_owned(),
+            fstype: "vfat".to_owned(),
+            mount_options: "defaults".to_owned(),
+        }
+    }
+}
+
+/// A bank is always formatted as ext4
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BankPartition {
+    pub device: String,
+    /// Filesystem label given to mkfs.ext4
+    pub label: String,
+    /// Mount options in addition to `defaults` for the root, and `noauto` for the other bank
+    pub mount_options: String,
+}
+
+/// Confirm a newly booted bank automatically, see bootconfirm.rs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootConfirmation {
+    pub enabled: bool,
+    /// How long the health checks may fail before we roll back
+    pub timeout_seconds: u64,
+    pub retry_interval_seconds: u64,
+}
+
+impl Default for BootConfirmation {
+    fn default() -> Self {
+        BootConfirmation {
+            enabled: false,
+            timeout_seconds: 300,
+            retry_interval_seconds: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootCounter {
+    /// Written to the U-Boot bootlimit when switching to the updated bank.
+    /// If not set, the bootlimit already in the environment is used.
+    pub bootlimit: Option<u32>,
+}
@@ -0,0 +1,61 @@
+//! The ZMQ sockets on which the daemon receives commands and publishes events.
+//!
+//! Any ZMQ endpoint can be used: `tcp://127.0.0.1:5552` for local clients only,
+//! `tcp://192.168.1.10:5552` to accept commands from the LAN, or `ipc:///run/firmware-update/control.sock`
+//! for a unix socket whose access is controlled with filesystem permissions.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{Command, Request};
+
+pub const DEFAULT_ENDPOINT : &str = "tcp://127.0.0.1:5552";
+pub const DEFAULT_EVENTS_ENDPOINT : &str = "tcp://127.0.0.1:5553";
+
+/// Endpoint through which the HTTP server forwards its commands to the daemon loop
+pub const INPROC_ENDPOINT : &str = "inproc://firmware-update";
+
+/// Bind the socket, and apply the permissions to the socket file of ipc:// endpoints
+pub fn bind(socket: &zmq::Socket, endpoint: &str, ipc_mode: Option<u32>) -> Result<(), Box<dyn std::error::Error>> {
+    let ipc_path = endpoint.strip_prefix("ipc://").map(Path::new);
+
+    if let Some(parent) = ipc_path.and_then(|p| p.parent()) {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Cannot create directory {} for {}: {}", parent.to_string_lossy(), endpoint, e))?;
+    }
+
+    socket.bind(endpoint)
+        .map_err(|e| format!("Cannot bind to {}: {}", endpoint, e))?;
+
+    if let (Some(path), Some(mode)) = (ipc_path, ipc_mode) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Cannot set permissions {:o} on {}: {}", mode, path.to_string_lossy(), e))?;
+    }
+
+    eprintln!("Listening on {}", endpoint);
+    Ok(())
+}
+
+/// Send one command to the daemon and return its JSON reply
+pub fn request(ctx: &zmq::Context, endpoint: &str, token: Option<&str>, command: Command, timeout: Duration) -> Result<String, Box<dyn std::error::Error>> {
+    let socket = ctx.socket(zmq::REQ)?;
+    socket.set_rcvtimeo(timeout.as_millis() as i32)?;
+    socket.set_linger(0)?;
+    socket.connect(endpoint)?;
+
+    let request = Request { token: token.map(|t| t.to_owned()), command };
+    socket.send(serde_json::to_string(&request)?.as_str(), 0)?;
+
+    match socket.recv_string(0) {
+        Ok(Ok(reply)) => Ok(reply),
+        Ok(Err(_)) => Err("Reply from daemon is not UTF-8".into()),
+        Err(zmq::Error::EAGAIN) => Err(format!("No reply from daemon at {}", endpoint).into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a CommandResult reports that the command failed
+pub fn is_failure(reply: &serde_json::Value) -> bool {
+    matches!(reply["status"].as_str(), Some("Error") | Some("Unauthorized") | Some("HealthChecksFailed"))
+}
@@ -0,0 +1,243 @@
+//! Delta images, which only carry the files that changed since the image the device runs.
+//!
+//! A delta image is a tar archive like a full image, whose first entry is the delta manifest
+//! `firmware-update-delta.json`:
+//!
+//! ```json
+//! {
+//!   "base_version": "2024-03-01T12:00:00Z",
+//!   "unchanged": { "usr/bin/odr-dabmod": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08" }
+//! }
+//! ```
+//!
+//! The archive contains the directories, symlinks and the files that changed. The files of the
+//! target image listed as unchanged are copied from the running root, and each copy is checked
+//! against its SHA-256 from the manifest, so that the other bank ends up identical to the full
+//! image. The manifest is covered by the digest and signature of the image, and the copy only
+//! starts once those are verified.
+//!
+//! The fstab and the files in firmware-update-filelist.txt differ between devices, so their
+//! digests are not checked. They are replaced by render_fstab and copy_config afterwards.
+//!
+//! firmware-update-make-delta.py creates delta images.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{check_cancelled, manifest, read_file_contents, ProgressPrinter, VERSION_FILENAME};
+
+pub const MANIFEST_PATH : &str = "firmware-update-delta.json";
+
+/// The unchanged files are taken from the bank we are running from
+pub const RUNNING_ROOT : &str = "/";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeltaManifest {
+    /// Content of image_built_at.txt of the image the delta was made against
+    pub base_version: String,
+    /// Files of the target image that are not in the archive, with their hex-encoded SHA-256
+    pub unchanged: BTreeMap<PathBuf, String>,
+}
+
+/// Read the delta manifest if `entry` is one, that is if the image is a delta image
+pub fn read_manifest(entry: &mut tar::Entry<impl Read>) -> Result<Option<DeltaManifest>, Box<dyn std::error::Error>> {
+    let path = entry.path()?.into_owned();
+    if path.strip_prefix(".").unwrap_or(&path) != Path::new(MANIFEST_PATH) {
+        return Ok(None);
+    }
+
+    let mut manifest : DeltaManifest = serde_json::from_reader(entry)
+        .map_err(|e| format!("Invalid delta manifest: {}", e))?;
+
+    for (path, digest) in manifest.unchanged.iter_mut() {
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("Invalid path {} in delta manifest", path.to_string_lossy()).into());
+        }
+        *digest = manifest::parse_sha256(digest)?;
+    }
+
+    eprintln!("Delta image against {}, {} unchanged files", manifest.base_version, manifest.unchanged.len());
+    Ok(Some(manifest))
+}
+
+/// Check that the running bank has the image the delta was made against
+pub fn check_base(manifest: &DeltaManifest, running_root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    match read_file_contents(&running_root.join(VERSION_FILENAME)) {
+        Some(version) if version == manifest.base_version => Ok(()),
+        Some(version) => Err(format!("Delta image applies to version {}, but we run {}", manifest.base_version, version).into()),
+        None => Err(format!("Delta image applies to version {}, but the version we run is unknown", manifest.base_version).into()),
+    }
+}
+
+/// Copy the unchanged files from the running root into the other bank, with their permissions,
+/// owner and modification time. The digest of the `rewritten` files is not checked, because
+/// they differ between devices and are replaced after the update anyway.
+/// `file_count` is the number of files extracted so far, the new count is returned.
+pub fn copy_unchanged(manifest: &DeltaManifest, running_root: &Path, bank_root: &Path, rewritten: &[PathBuf], printer: &mut ProgressPrinter, mut file_count: usize, cancel_requested: &AtomicBool) -> Result<usize, Box<dyn std::error::Error>> {
+    eprintln!("Copy {} unchanged files from {}", manifest.unchanged.len(), running_root.to_string_lossy());
+
+    let mut buf = vec![0; 64 * 1024];
+    for (path, expected) in &manifest.unchanged {
+        check_cancelled(cancel_requested)?;
+
+        let source = running_root.join(path);
+        let target = bank_root.join(path);
+
+        let metadata = std::fs::symlink_metadata(&source)
+            .map_err(|e| format!("Cannot copy {} from the running bank: {}", path.to_string_lossy(), e))?;
+        if !metadata.is_file() {
+            return Err(format!("{} is not a regular file in the running bank", path.to_string_lossy()).into());
+        }
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut input = File::open(&source)?;
+        let mut output = File::options().write(true).create_new(true).open(&target)
+            .map_err(|e| format!("Cannot create {}: {}", target.to_string_lossy(), e))?;
+
+        let mut hasher = Sha256::new();
+        loop {
+            let n = input.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            output.write_all(&buf[..n])?;
+        }
+
+        let digest = format!("{:x}", hasher.finalize());
+        if digest != *expected && !rewritten.contains(path) {
+            return Err(format!("{} in the running bank differs from the base of the delta image, a full image is needed", path.to_string_lossy()).into());
+        }
+
+        // chown clears the setuid and setgid bits, so it comes before the permissions
+        std::os::unix::fs::fchown(&output, Some(metadata.uid()), Some(metadata.gid()))?;
+        output.set_permissions(metadata.permissions())?;
+        output.set_modified(metadata.modified()?)?;
+
+        file_count += 1;
+        printer.progress_state.files_extracted.store(file_count, Ordering::Relaxed);
+        printer.tick(file_count);
+    }
+
+    Ok(file_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::events::Publisher;
+    use crate::ProgressState;
+
+    fn temp_dir() -> PathBuf {
+        static COUNTER : AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("delta-test-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sha256(contents: &str) -> String {
+        format!("{:x}", Sha256::digest(contents.as_bytes()))
+    }
+
+    /// A delta image with only the manifest
+    fn delta_image(manifest: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, MANIFEST_PATH, manifest.as_bytes()).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn read_delta_manifest(image: &[u8]) -> Result<Option<DeltaManifest>, Box<dyn std::error::Error>> {
+        let mut archive = tar::Archive::new(image);
+        let mut entry = archive.entries()?.next().unwrap()?;
+        read_manifest(&mut entry)
+    }
+
+    /// Copy the unchanged files into a new directory, removed with the running root
+    fn apply(manifest: &DeltaManifest, running_root: &Path, rewritten: &[PathBuf]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        static COUNTER : AtomicUsize = AtomicUsize::new(0);
+        let bank_root = running_root.join(format!("bank-{}", COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let progress_state = ProgressState::new(Publisher::disabled());
+        let mut printer = ProgressPrinter::new(progress_state.bytes_downloaded.clone(), None, progress_state);
+        copy_unchanged(manifest, running_root, &bank_root, rewritten, &mut printer, 0, &AtomicBool::new(false))?;
+        Ok(bank_root)
+    }
+
+    #[test]
+    fn device_specific_files_differ() {
+        let running_root = temp_dir();
+        std::fs::create_dir_all(running_root.join("etc")).unwrap();
+        std::fs::create_dir_all(running_root.join("usr/bin")).unwrap();
+        std::fs::write(running_root.join(VERSION_FILENAME), "v1\n").unwrap();
+        std::fs::write(running_root.join("usr/bin/app"), "app").unwrap();
+        std::fs::write(running_root.join("etc/hostname"), "transmitter-42\n").unwrap();
+
+        let image = delta_image(&format!(r#"{{"base_version": "v1", "unchanged": {{"usr/bin/app": "{}", "etc/hostname": "{}"}}}}"#,
+            sha256("app"), sha256("dexter\n")));
+        let manifest = read_delta_manifest(&image).unwrap().unwrap();
+        check_base(&manifest, &running_root).unwrap();
+
+        let bank_root = apply(&manifest, &running_root, &[PathBuf::from("etc/hostname"), PathBuf::from("etc/fstab")]).unwrap();
+        assert_eq!(std::fs::read_to_string(bank_root.join("usr/bin/app")).unwrap(), "app");
+        assert_eq!(std::fs::read_to_string(bank_root.join("etc/hostname")).unwrap(), "transmitter-42\n");
+
+        let e = apply(&manifest, &running_root, &[]).unwrap_err();
+        assert!(e.to_string().contains("etc/hostname"), "{}", e);
+
+        // Other files are still checked
+        std::fs::write(running_root.join("usr/bin/app"), "modified").unwrap();
+        let e = apply(&manifest, &running_root, &[PathBuf::from("etc/hostname")]).unwrap_err();
+        assert!(e.to_string().contains("usr/bin/app"), "{}", e);
+
+        std::fs::remove_dir_all(running_root).unwrap();
+    }
+
+    #[test]
+    fn wrong_base() {
+        let running_root = temp_dir();
+        std::fs::write(running_root.join(VERSION_FILENAME), "v2\n").unwrap();
+
+        let manifest = read_delta_manifest(&delta_image(r#"{"base_version": "v1", "unchanged": {}}"#)).unwrap().unwrap();
+        assert!(check_base(&manifest, &running_root).is_err());
+
+        std::fs::remove_dir_all(running_root).unwrap();
+    }
+
+    #[test]
+    fn paths_outside_the_bank() {
+        let digest = sha256("");
+        for path in ["../etc/shadow", "usr/../../etc/shadow", "/etc/shadow"] {
+            let image = delta_image(&format!(r#"{{"base_version": "v1", "unchanged": {{"{}": "{}"}}}}"#, path, digest));
+            assert!(read_delta_manifest(&image).is_err(), "{}", path);
+        }
+
+        let image = delta_image(&format!(r#"{{"base_version": "v1", "unchanged": {{"usr/bin/app": "{}"}}}}"#, digest));
+        assert!(read_delta_manifest(&image).unwrap().is_some());
+    }
+
+    #[test]
+    fn full_image() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, VERSION_FILENAME, &b""[..]).unwrap();
+
+        assert!(read_delta_manifest(&builder.into_inner().unwrap()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,181 @@
+//! Download of the image that survives connection losses.
+//!
+//! When the connection breaks or stalls, the download continues with a Range request from the
+//! last byte received, after a backoff delay. The reader is below the decompressor, the digest
+//! and the signature check, so they see one uninterrupted stream and the extraction carries on.
+
+use std::io::{Error, ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::config::Download;
+use crate::{with_credentials, Cancelled, Credentials};
+
+pub struct ResumableReader {
+    agent : ureq::Agent,
+    url : String,
+    creds : Option<Credentials>,
+    settings : Download,
+    cancel_requested : Arc<AtomicBool>,
+
+    reader : Box<dyn Read + Send + Sync>,
+    position : u64,
+    content_length : Option<u64>,
+    /// ETag or Last-Modified of the first response, so that we only resume the same image
+    validator : Option<String>,
+    retries : u32,
+}
+
+/// Agent with the configured timeouts, for the image and the files published next to it
+pub fn agent(settings: &Download) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(settings.connect_timeout_seconds))
+        .timeout_read(Duration::from_secs(settings.read_timeout_seconds))
+        .build()
+}
+
+/// Whether the request failed because of the connection or the server, and could succeed later
+fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => *code >= 500 || *code == 408 || *code == 429,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+impl ResumableReader {
+    /// Start the download, retrying within the budget if the server cannot be reached
+    pub fn open(url: &str, creds: Option<Credentials>, settings: &Download, cancel_requested: Arc<AtomicBool>) -> Result<Self, Box<dyn std::error::Error>> {
+        let agent = agent(settings);
+
+        let mut retries = 0;
+        let response = loop {
+            match with_credentials(agent.get(url), &creds).call() {
+                Ok(response) => break response,
+                Err(e) if is_transient(&e) && retries < settings.max_retries => {
+                    retries += 1;
+                    eprintln!("Cannot download {}: {}", url, e);
+                    if !backoff(settings, retries, &cancel_requested) {
+                        return Err(Box::new(Cancelled));
+                    }
+                },
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let content_length = response.header("content-length").and_then(|v| v.parse().ok());
+        let validator = response.header("etag")
+            .filter(|etag| !etag.starts_with("W/"))
+            .or_else(|| response.header("last-modified"))
+            .map(|v| v.to_owned());
+
+        Ok(ResumableReader {
+            agent,
+            url: url.to_owned(),
+            creds,
+            settings: settings.clone(),
+            cancel_requested,
+            reader: response.into_reader(),
+            position: 0,
+            content_length,
+            validator,
+            retries,
+        })
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    /// Reconnect after `error` and continue from the current position. Fails when the retry
+    /// budget is exhausted, the update is cancelled, or the server cannot resume.
+    fn resume(&mut self, mut error: Error) -> std::io::Result<()> {
+        loop {
+            if self.retries >= self.settings.max_retries {
+                return Err(Error::new(error.kind(), format!("Download failed after {} retries: {}", self.retries, error)));
+            }
+            self.retries += 1;
+
+            eprintln!("Download interrupted at byte {}: {}", self.position, error);
+            if !backoff(&self.settings, self.retries, &self.cancel_requested) {
+                return Err(cancelled());
+            }
+
+            let mut request = with_credentials(self.agent.get(&self.url), &self.creds)
+                .set("Range", &format!("bytes={}-", self.position));
+            if let Some(validator) = &self.validator {
+                request = request.set("If-Range", validator);
+            }
+
+            let response = match request.call() {
+                Ok(response) => response,
+                Err(e) if is_transient(&e) => {
+                    error = Error::other(e.to_string());
+                    continue;
+                },
+                Err(e) => return Err(Error::other(format!("Cannot resume download: {}", e))),
+            };
+
+            let expected_range = format!("bytes {}-", self.position);
+            match (response.status(), response.header("content-range")) {
+                (206, Some(range)) if range.starts_with(&expected_range) => (),
+                (206, range) =>
+                    return Err(Error::other(format!("Cannot resume download: server sent range {:?} instead of {}", range, expected_range))),
+                (_, _) if self.validator.is_some() =>
+                    return Err(Error::other("Cannot resume download: the image changed on the server, or the server does not support Range requests")),
+                (_, _) =>
+                    return Err(Error::other("Cannot resume download: the server does not support Range requests")),
+            }
+
+            eprintln!("Download resumed at byte {}", self.position);
+            self.reader = response.into_reader();
+            return Ok(());
+        }
+    }
+}
+
+impl Read for ResumableReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let error = match self.reader.read(buf) {
+                Ok(0) if !buf.is_empty() && self.content_length.is_some_and(|l| self.position < l) =>
+                    Error::new(ErrorKind::UnexpectedEof, "Connection closed before the end of the image"),
+                Ok(n) => {
+                    self.position += n as u64;
+                    return Ok(n);
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+
+            // The reader is dead, and retrying it would only use up the retry budget
+            if self.cancel_requested.load(Ordering::Relaxed) {
+                return Err(cancelled());
+            }
+            self.resume(error)?;
+        }
+    }
+}
+
+/// Not ErrorKind::Interrupted, which the callers of read() take as a reason to read again
+fn cancelled() -> Error {
+    Error::other(Cancelled)
+}
+
+/// Wait before retry number `attempt`, doubling the delay every time up to the maximum.
+/// Returns false if the update got cancelled in the meantime.
+fn backoff(settings: &Download, attempt: u32, cancel_requested: &AtomicBool) -> bool {
+    let delay = settings.initial_backoff_seconds
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(settings.max_backoff_seconds);
+    eprintln!("Retry {}/{} in {} s", attempt, settings.max_retries, delay);
+
+    for _ in 0..delay {
+        if cancel_requested.load(Ordering::Relaxed) {
+            return false;
+        }
+        sleep(Duration::from_secs(1));
+    }
+    !cancel_requested.load(Ordering::Relaxed)
+}
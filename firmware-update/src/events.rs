@@ -0,0 +1,61 @@
+//! Events published on a ZMQ PUB socket, so that clients can subscribe instead of polling GetStatus.
+//!
+//! Every event is a message with two frames: the event name, which can be used as subscription
+//! prefix, and the event as JSON, tagged with the same name in the "event" field.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Sender};
+
+use serde::Serialize;
+
+use crate::{LastUpdate, Progress, UpdatePhase};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    /// The update started a new phase
+    UpdatePhase { phase: UpdatePhase },
+    /// Sent about once per second while the image is downloaded and extracted
+    UpdateProgress { progress: Progress },
+    /// The update succeeded, failed or was cancelled
+    UpdateFinished { last_update: LastUpdate },
+    /// U-Boot environment variables were changed. A None value means the variable was removed.
+    UBootEnvChanged { reason: String, variables: BTreeMap<String, Option<String>> },
+}
+
+/// Handle to publish events from any thread. The socket is owned by a dedicated thread,
+/// because ZMQ sockets cannot be shared between threads.
+#[derive(Clone)]
+pub struct Publisher {
+    sender: Option<Sender<Event>>,
+}
+
+impl Publisher {
+    /// A publisher that drops all events
+    pub fn disabled() -> Self {
+        Publisher { sender: None }
+    }
+
+    pub fn start(socket: zmq::Socket) -> Self {
+        let (sender, receiver) = channel::<Event>();
+
+        std::thread::spawn(move || {
+            for event in receiver {
+                let json = serde_json::to_value(&event).expect("serialize to JSON");
+                let name = json["event"].as_str().unwrap_or_default().to_owned();
+                if let Err(e) = socket.send_multipart([name.as_bytes(), json.to_string().as_bytes()], 0) {
+                    eprintln!("Cannot publish event {}: {}", name, e);
+                }
+            }
+        });
+
+        Publisher { sender: Some(sender) }
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Some(sender) = &self.sender {
+            // Only fails if the publisher thread is gone, in which case nobody can receive the event
+            let _ = sender.send(event);
+        }
+    }
+}
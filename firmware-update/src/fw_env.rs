@@ -0,0 +1,484 @@
+//! Read and write the U-Boot environment directly, like fw_printenv and fw_setenv do.
+//!
+//! The locations of the environment are taken from a fw_env.config file, which can point
+//! to block devices as well as to plain image files. With two locations, the environment
+//! is redundant: each copy has a flag byte after the CRC that counts up on every write, and
+//! we always write to the copy that is not the current one.
+//!
+//! Like fw_setenv, an environment with a bad CRC, e.g. one that was never saved, is replaced
+//! by the default environment. As we do not have the one built into U-Boot, it is read from
+//! DEFAULT_ENV_PATH, so that writing does not lose bootcmd and friends.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const FW_ENV_CONFIG_PATH : &str = "/etc/fw_env.config";
+/// `name=value` lines, e.g. from `fw_printenv` on a device whose env was saved
+pub const DEFAULT_ENV_PATH : &str = "/etc/firmware-update/uboot-env.default";
+
+const CRC_SIZE : usize = 4;
+const FLAGS_SIZE : usize = 1;
+
+#[derive(Debug, Clone)]
+pub struct EnvLocation {
+    pub device : PathBuf,
+    pub offset : u64,
+    pub size : usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct FwEnvConfig {
+    pub locations : Vec<EnvLocation>,
+}
+
+impl FwEnvConfig {
+    pub fn load(path: &Path) -> Result<FwEnvConfig, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.to_string_lossy(), e))?;
+        Self::parse(&contents)
+    }
+
+    /// Parse lines of `device offset size [sector-size [sectors]]`, numbers in decimal or 0x hex.
+    /// The sector information is only needed for MTD devices, which we do not support.
+    pub fn parse(contents: &str) -> Result<FwEnvConfig, Box<dyn std::error::Error>> {
+        let mut locations = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                return Err(format!("Invalid fw_env.config line '{}'", line).into());
+            }
+            if fields[0].starts_with("/dev/mtd") {
+                // They must be erased before writing, which we do not do
+                return Err(format!("MTD device {} is not supported", fields[0]).into());
+            }
+
+            let size = parse_number(fields[2])? as usize;
+            if size <= CRC_SIZE + FLAGS_SIZE {
+                return Err(format!("Environment size {} is too small", size).into());
+            }
+
+            locations.push(EnvLocation {
+                device: PathBuf::from(fields[0]),
+                offset: parse_number(fields[1])?,
+                size,
+            });
+        }
+
+        match locations.len() {
+            1 | 2 => Ok(FwEnvConfig { locations }),
+            n => Err(format!("fw_env.config must contain one or two environments, not {}", n).into()),
+        }
+    }
+
+    pub fn is_redundant(&self) -> bool {
+        self.locations.len() == 2
+    }
+
+    fn header_size(&self) -> usize {
+        if self.is_redundant() { CRC_SIZE + FLAGS_SIZE } else { CRC_SIZE }
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let r = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    r.map_err(|e| format!("Invalid number '{}': {}", s, e).into())
+}
+
+/// One copy of the environment as it is stored
+struct EnvCopy {
+    crc_ok : bool,
+    flags : u8,
+    data : Vec<u8>,
+}
+
+fn read_copy(location: &EnvLocation, header_size: usize) -> Result<EnvCopy, Box<dyn std::error::Error>> {
+    let mut file = File::open(&location.device)
+        .map_err(|e| format!("Cannot open {}: {}", location.device.to_string_lossy(), e))?;
+    file.seek(SeekFrom::Start(location.offset))?;
+
+    let mut block = vec![0u8; location.size];
+    file.read_exact(&mut block)?;
+
+    let stored_crc = u32::from_le_bytes(block[..CRC_SIZE].try_into().expect("4 bytes"));
+    let flags = if header_size > CRC_SIZE { block[CRC_SIZE] } else { 0 };
+    let data = block.split_off(header_size);
+
+    Ok(EnvCopy {
+        crc_ok: crc32fast::hash(&data) == stored_crc,
+        flags,
+        data,
+    })
+}
+
+/// Which of the two redundant copies is the current one, same rules as fw_env.c
+fn current_copy(copies: &[EnvCopy; 2]) -> Option<usize> {
+    match (copies[0].crc_ok, copies[1].crc_ok) {
+        (false, false) => None,
+        (true, false) => Some(0),
+        (false, true) => Some(1),
+        (true, true) => {
+            let (f0, f1) = (copies[0].flags, copies[1].flags);
+            if f0 == 0xFF && f1 == 0 {
+                Some(1)
+            }
+            else if f1 == 0xFF && f0 == 0 {
+                Some(0)
+            }
+            else if f1 > f0 {
+                Some(1)
+            }
+            else {
+                Some(0)
+            }
+        },
+    }
+}
+
+fn parse_vars(data: &[u8]) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    for entry in data.split(|b| *b == 0) {
+        // Two consecutive NULs end the environment
+        if entry.is_empty() {
+            break;
+        }
+
+        let entry = String::from_utf8_lossy(entry);
+        match entry.split_once('=') {
+            Some((name, value)) => { vars.insert(name.to_owned(), value.to_owned()); },
+            None => eprintln!("Ignoring invalid U-Boot env entry '{}'", entry),
+        }
+    }
+    vars
+}
+
+fn read_default_vars(path: &Path) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        eprintln!("No default environment in {}, starting from an empty one", path.to_string_lossy());
+        return Ok(BTreeMap::new());
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {}", path.to_string_lossy(), e))?;
+
+    let mut vars = BTreeMap::new();
+    for line in contents.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, value)) => { vars.insert(name.to_owned(), value.to_owned()); },
+            None => return Err(format!("Invalid line '{}' in {}", line, path.to_string_lossy()).into()),
+        }
+    }
+    Ok(vars)
+}
+
+pub struct Environment {
+    config : FwEnvConfig,
+    current : usize,
+    flags : u8,
+    vars : BTreeMap<String, String>,
+}
+
+impl Environment {
+    /// Read the environment from the locations given in /etc/fw_env.config
+    pub fn read_default() -> Result<Environment, Box<dyn std::error::Error>> {
+        Self::read(FwEnvConfig::load(Path::new(FW_ENV_CONFIG_PATH))?)
+    }
+
+    pub fn read(config: FwEnvConfig) -> Result<Environment, Box<dyn std::error::Error>> {
+        Self::read_with_default(config, Path::new(DEFAULT_ENV_PATH))
+    }
+
+    /// Read the environment, or the default one from `default_path` if no copy has a valid CRC
+    pub fn read_with_default(config: FwEnvConfig, default_path: &Path) -> Result<Environment, Box<dyn std::error::Error>> {
+        let header_size = config.header_size();
+
+        let copy = if config.is_redundant() {
+            let copies = [
+                read_copy(&config.locations[0], header_size)?,
+                read_copy(&config.locations[1], header_size)?,
+            ];
+            current_copy(&copies).map(|current| {
+                let [c0, c1] = copies;
+                (current, if current == 0 { c0 } else { c1 })
+            })
+        }
+        else {
+            let copy = read_copy(&config.locations[0], header_size)?;
+            if copy.crc_ok { Some((0, copy)) } else { None }
+        };
+
+        let (current, flags, vars) = match copy {
+            Some((current, copy)) => (current, copy.flags, parse_vars(&copy.data)),
+            None => {
+                // The first write goes to the second copy with flag 1, which then becomes current
+                eprintln!("Bad CRC in U-Boot environment, using the default environment");
+                (0, 0, read_default_vars(default_path)?)
+            },
+        };
+
+        Ok(Environment {
+            config,
+            current,
+            flags,
+            vars,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|v| v.as_str())
+    }
+
+    pub fn vars(&self) -> &BTreeMap<String, String> {
+        &self.vars
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if name.is_empty() || name.contains('=') || name.contains('\0') || value.contains('\0') {
+            return Err(format!("Invalid U-Boot env variable {}={}", name, value).into());
+        }
+        self.vars.insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.vars.remove(name);
+    }
+
+    /// Write all variables in one go. With a redundant environment, the copy that is not
+    /// current gets overwritten, so that the current one stays valid if this is interrupted.
+    pub fn write(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let header_size = self.config.header_size();
+        let target = if self.config.is_redundant() { 1 - self.current } else { 0 };
+        let location = &self.config.locations[target];
+
+        let mut data = Vec::new();
+        for (name, value) in &self.vars {
+            data.extend_from_slice(name.as_bytes());
+            data.push(b'=');
+            data.extend_from_slice(value.as_bytes());
+            data.push(0);
+        }
+        // Terminating NUL
+        data.push(0);
+
+        let data_size = location.size - header_size;
+        if data.len() > data_size {
+            return Err(format!("U-Boot environment too large: {} > {} bytes", data.len(), data_size).into());
+        }
+        data.resize(data_size, 0);
+
+        let flags = self.flags.wrapping_add(1);
+
+        let mut block = Vec::with_capacity(location.size);
+        block.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        if self.config.is_redundant() {
+            block.push(flags);
+        }
+        block.extend_from_slice(&data);
+
+        let mut file = File::options()
+            .write(true)
+            .open(&location.device)
+            .map_err(|e| format!("Cannot open {}: {}", location.device.to_string_lossy(), e))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.write_all(&block)?;
+        file.sync_all()?;
+
+        if self.config.is_redundant() {
+            self.current = target;
+            self.flags = flags;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const SIZE : usize = 0x100;
+
+    /// An env block as U-Boot stores it, with the flag byte if given
+    fn block(vars: &[&str], flags: Option<u8>) -> Vec<u8> {
+        let header_size = if flags.is_some() { CRC_SIZE + FLAGS_SIZE } else { CRC_SIZE };
+        let mut data = Vec::new();
+        for var in vars {
+            data.extend_from_slice(var.as_bytes());
+            data.push(0);
+        }
+        data.resize(SIZE - header_size, 0);
+
+        let mut block = crc32fast::hash(&data).to_le_bytes().to_vec();
+        block.extend(flags);
+        block.extend_from_slice(&data);
+        block
+    }
+
+    /// Write the blocks one after the other into a new image file, and return its config
+    fn image(blocks: &[Vec<u8>]) -> (PathBuf, FwEnvConfig) {
+        static COUNTER : AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!("fw_env-test-{}-{}.img", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        std::fs::write(&path, blocks.concat()).unwrap();
+
+        let config = blocks.iter().enumerate()
+            .map(|(i, _)| format!("{} 0x{:x} 0x{:x}\n", path.to_string_lossy(), i * SIZE, SIZE))
+            .collect::<String>();
+        (path, FwEnvConfig::parse(&config).unwrap())
+    }
+
+    fn read_block(path: &Path, index: usize) -> Vec<u8> {
+        std::fs::read(path).unwrap()[index * SIZE..(index + 1) * SIZE].to_vec()
+    }
+
+    #[test]
+    fn single_read_and_write() {
+        let (path, config) = image(&[block(&["bootdelay=3", "desired_bank=A"], None)]);
+
+        let mut env = Environment::read(config.clone()).unwrap();
+        assert_eq!(env.get("desired_bank"), Some("A"));
+        assert_eq!(env.get("bootdelay"), Some("3"));
+
+        env.set("desired_bank", "B").unwrap();
+        env.unset("bootdelay");
+        env.write().unwrap();
+
+        assert_eq!(read_block(&path, 0), block(&["desired_bank=B"], None));
+        let env = Environment::read(config).unwrap();
+        assert_eq!(env.get("desired_bank"), Some("B"));
+        assert_eq!(env.get("bootdelay"), None);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn single_bad_crc() {
+        let mut corrupted = block(&["desired_bank=A"], None);
+        corrupted[CRC_SIZE] ^= 1;
+        let (path, config) = image(&[corrupted]);
+
+        // Starts from the default environment, and writing makes it valid
+        let default_path = path.with_extension("default");
+        std::fs::write(&default_path, "# saved with fw_printenv\nbootcmd=run bank_boot\nbootdelay=3\n").unwrap();
+        let mut env = Environment::read_with_default(config.clone(), &default_path).unwrap();
+        assert_eq!(env.get("bootcmd"), Some("run bank_boot"));
+        assert_eq!(env.get("desired_bank"), None);
+
+        env.set("desired_bank", "B").unwrap();
+        env.write().unwrap();
+        assert_eq!(read_block(&path, 0), block(&["bootcmd=run bank_boot", "bootdelay=3", "desired_bank=B"], None));
+
+        std::fs::remove_file(default_path).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn redundant_highest_flag_is_current() {
+        let (path, config) = image(&[block(&["desired_bank=A"], Some(1)), block(&["desired_bank=B"], Some(2))]);
+
+        let mut env = Environment::read(config.clone()).unwrap();
+        assert_eq!(env.get("desired_bank"), Some("B"));
+
+        // The current copy stays as it was, the other one gets the next flag
+        env.set("desired_bank", "A").unwrap();
+        env.write().unwrap();
+        assert_eq!(read_block(&path, 0), block(&["desired_bank=A"], Some(3)));
+        assert_eq!(read_block(&path, 1), block(&["desired_bank=B"], Some(2)));
+
+        // And becomes the current one
+        env.set("desired_bank", "B").unwrap();
+        env.write().unwrap();
+        assert_eq!(read_block(&path, 1), block(&["desired_bank=B"], Some(4)));
+
+        assert_eq!(Environment::read(config).unwrap().get("desired_bank"), Some("B"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn redundant_flag_wraparound() {
+        let (path, config) = image(&[block(&["desired_bank=A"], Some(0xFF)), block(&["desired_bank=B"], Some(0))]);
+
+        let mut env = Environment::read(config.clone()).unwrap();
+        assert_eq!(env.get("desired_bank"), Some("B"));
+
+        env.write().unwrap();
+        assert_eq!(read_block(&path, 0), block(&["desired_bank=B"], Some(1)));
+
+        std::fs::remove_file(path).unwrap();
+
+        let (path, config) = image(&[block(&["desired_bank=A"], Some(0)), block(&["desired_bank=B"], Some(0xFF))]);
+        assert_eq!(Environment::read(config).unwrap().get("desired_bank"), Some("A"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn redundant_bad_crc() {
+        let mut corrupted = block(&["desired_bank=B"], Some(2));
+        corrupted[CRC_SIZE + FLAGS_SIZE] ^= 1;
+        let (path, config) = image(&[block(&["desired_bank=A"], Some(1)), corrupted.clone()]);
+
+        // The valid copy is current even with the lower flag, and the corrupted one is overwritten
+        let mut env = Environment::read(config.clone()).unwrap();
+        assert_eq!(env.get("desired_bank"), Some("A"));
+        env.write().unwrap();
+        assert_eq!(read_block(&path, 1), block(&["desired_bank=A"], Some(2)));
+
+        // Without a default environment, both bad copies give an empty one
+        std::fs::write(&path, [corrupted.clone(), corrupted].concat()).unwrap();
+        let mut env = Environment::read_with_default(config.clone(), &path.with_extension("missing")).unwrap();
+        assert!(env.vars().is_empty());
+
+        env.set("desired_bank", "A").unwrap();
+        env.write().unwrap();
+        assert_eq!(read_block(&path, 1), block(&["desired_bank=A"], Some(1)));
+        assert_eq!(Environment::read(config).unwrap().get("desired_bank"), Some("A"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn too_large() {
+        let original = block(&["desired_bank=A"], None);
+        let (path, config) = image(std::slice::from_ref(&original));
+
+        let mut env = Environment::read(config).unwrap();
+        env.set("bootargs", &"x".repeat(SIZE)).unwrap();
+        let e = env.write().unwrap_err();
+        assert!(e.to_string().contains("too large"), "{}", e);
+
+        // Nothing was written
+        assert_eq!(read_block(&path, 0), original);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = FwEnvConfig::parse("# comment\n/dev/mmcblk0 0x3FE000 0x2000\n/dev/mmcblk0 4194304 8192 0x200\n").unwrap();
+        assert!(config.is_redundant());
+        assert_eq!(config.locations[0].offset, 0x3FE000);
+        assert_eq!(config.locations[1].offset, 0x3FE000 + 0x2000);
+        assert_eq!(config.locations[1].size, 0x2000);
+
+        assert!(FwEnvConfig::parse("").is_err());
+        assert!(FwEnvConfig::parse("/dev/mmcblk0 0x0").is_err());
+        assert!(FwEnvConfig::parse("/dev/mmcblk0 0x0 4").is_err());
+        assert!(FwEnvConfig::parse("/dev/mtd1 0x0 0x10000 0x10000").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+const IIO_DEVICES_PATH : &str = "/sys/bus/iio/devices";
+
+/// How often a running check is polled for completion
+const POLL_INTERVAL : Duration = Duration::from_millis(100);
+
+/// A check that tells if the running image works, configured in `[[health_checks]]`
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheck {
+    /// A check that is not required is run and reported, but does not prevent SetBankOk
+    #[serde(default = "default_required")]
+    pub required: bool,
+
+    /// A systemd_unit or command check still running after this long is killed and fails
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+
+    #[serde(flatten)]
+    pub kind: CheckKind,
+}
+
+fn default_required() -> bool { true }
+
+fn default_timeout_seconds() -> u64 { 30 }
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CheckKind {
+    /// `systemctl is-active` says the unit is active
+    SystemdUnit { unit: String },
+
+    /// An IIO device with this name exists under /sys/bus/iio/devices
+    IioDevice { name: String },
+
+    /// Run a command with `sh -c`, passes if it exits with 0
+    Command { command: String },
+
+    /// The file or directory exists
+    FileExists { path: PathBuf },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub check: String,
+    pub required: bool,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    /// All required checks passed
+    pub passed: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthReport {
+    /// The failed required checks, for the logs
+    pub fn failures(&self) -> String {
+        self.checks.iter()
+            .filter(|c| c.required && !c.passed)
+            .map(|c| format!("{}: {}", c.check, c.error.as_deref().unwrap_or("failed")))
+            .collect::<Vec<String>>()
+            .join("; ")
+    }
+}
+
+impl CheckKind {
+    pub fn describe(&self) -> String {
+        match self {
+            CheckKind::SystemdUnit { unit } => format!("systemd unit {} is active", unit),
+            CheckKind::IioDevice { name } => format!("IIO device {} exists", name),
+            CheckKind::Command { command } => format!("'{}' succeeds", command),
+            CheckKind::FileExists { path } => format!("{} exists", path.to_string_lossy()),
+        }
+    }
+
+    pub fn run(&self, timeout: Duration) -> Result<(), String> {
+        match self {
+            CheckKind::SystemdUnit { unit } => {
+                let mut command = Command::new("systemctl");
+                command.arg("is-active").arg("--quiet").arg(unit);
+                let status = run_with_timeout(&mut command, timeout)
+                    .map_err(|e| format!("systemctl: {}", e))?;
+
+                if status.success() {
+                    Ok(())
+                }
+                else {
+                    Err(format!("{} is not active", unit))
+                }
+            },
+            CheckKind::IioDevice { name } => {
+                if iio_device_exists(name)? {
+                    Ok(())
+                }
+                else {
+                    Err(format!("No IIO device named {}", name))
+                }
+            },
+            CheckKind::Command { command } => {
+                let status = run_with_timeout(Command::new("sh").arg("-c").arg(command), timeout)
+                    .map_err(|e| format!("'{}': {}", command, e))?;
+
+                if status.success() {
+                    Ok(())
+                }
+                else {
+                    Err(format!("'{}' failed with {}", command, status))
+                }
+            },
+            CheckKind::FileExists { path } => {
+                if path.exists() {
+                    Ok(())
+                }
+                else {
+                    Err(format!("{} does not exist", path.to_string_lossy()))
+                }
+            },
+        }
+    }
+}
+
+/// Run the command, and kill it if it does not exit within the timeout. The checks run on the
+/// thread that handles the commands, which must not hang.
+fn run_with_timeout(command: &mut Command, timeout: Duration) -> Result<ExitStatus, String> {
+    let mut child = command.spawn()
+        .map_err(|e| format!("Cannot run: {}", e))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) if Instant::now() < deadline => sleep(POLL_INTERVAL),
+            Ok(None) => {
+                if let Err(e) = child.kill() {
+                    eprintln!("Cannot kill health check: {}", e);
+                }
+                let _ = child.wait();
+                return Err(format!("Timed out after {} s", timeout.as_secs()));
+            },
+            Err(e) => return Err(format!("Cannot wait: {}", e)),
+        }
+    }
+}
+
+fn iio_device_exists(name: &str) -> Result<bool, String> {
+    let entries = std::fs::read_dir(IIO_DEVICES_PATH)
+        .map_err(|e| format!("Cannot read {}: {}", IIO_DEVICES_PATH, e))?;
+
+    for entry in entries.flatten() {
+        let device_name = std::fs::read_to_string(entry.path().join("name"));
+        if device_name.is_ok_and(|n| n.trim() == name) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Run all checks and report on each of them
+pub fn run_checks(checks: &[HealthCheck]) -> HealthReport {
+    let checks : Vec<CheckResult> = checks.iter()
+        .map(|c| {
+            let r = c.kind.run(Duration::from_secs(c.timeout_seconds));
+            CheckResult {
+                check: c.kind.describe(),
+                required: c.required,
+                passed: r.is_ok(),
+                error: r.err(),
+            }
+        })
+        .collect();
+
+    HealthReport {
+        passed: checks.iter().all(|c| c.passed || !c.required),
+        checks,
+    }
+}
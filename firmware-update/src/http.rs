@@ -0,0 +1,210 @@
+//! Optional REST API, for clients that speak HTTP rather than ZMQ.
+//!
+//! Every request is translated to a `Command` and forwarded to the daemon loop over the inproc
+//! endpoint, so that the commands are handled exactly like those received over ZMQ. The reply
+//! is the `CommandResult` as JSON.
+//!
+//! | Route                          | Command         | Body                                       |
+//! |--------------------------------|-----------------|--------------------------------------------|
+//! | GET /status                    | GetStatus       |                                            |
+//! | POST /update                   | Update          | {from_url, username, password, sha256}     |
+//! | POST /update/cancel            | CancelUpdate    |                                            |
+//! | POST /banks/other/format       | FormatOtherBank |                                            |
+//! | POST /banks/other/copy-config  | CopyConfig      |                                            |
+//! | POST /banks/desired            | SetDesiredBank  | {bank}                                     |
+//! | POST /banks/ok                 | SetBankOk       |                                            |
+//! | POST /health-checks            | RunHealthChecks |                                            |
+//! | GET /uboot-env                 | GetUBootEnv     |                                            |
+//! | POST /uboot-env                | SetUBootEnv     | {variables}                                |
+//!
+//! GET /events is a Server-Sent Events stream that pushes the Status every time it changes,
+//! so that clients can follow the progress of an update without polling.
+//!
+//! When tokens are configured, they are given in an `Authorization: Bearer <token>` header.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::config::Http;
+use crate::control;
+use crate::Command;
+
+/// Some commands, like FormatOtherBank, take a while before the daemon replies
+const COMMAND_TIMEOUT : Duration = Duration::from_secs(120);
+const MAX_BODY_SIZE : u64 = 64 * 1024;
+
+/// How often the events stream checks the status for changes
+const EVENTS_INTERVAL : Duration = Duration::from_secs(1);
+/// Send a comment after this many unchanged intervals, to notice clients that went away
+const EVENTS_KEEPALIVE : u32 = 15;
+
+pub fn spawn(ctx: zmq::Context, settings: &Http) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Server::http(&settings.listen)
+        .map_err(|e| format!("Cannot listen on {}: {}", settings.listen, e))?;
+    eprintln!("HTTP API listening on {}", settings.listen);
+
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if *request.method() == Method::Get && path(&request) == "/events" {
+                let ctx = ctx.clone();
+                std::thread::spawn(move || stream_events(&ctx, request));
+            }
+            else {
+                handle_request(&ctx, request);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn path(request: &Request) -> &str {
+    request.url().split('?').next().unwrap_or_default()
+}
+
+fn bearer_token(request: &Request) -> Option<String> {
+    request.headers().iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .map(|t| t.trim().to_owned())
+}
+
+/// The command name for a route, as in the "command" tag of `Command`
+fn route(method: &Method, path: &str) -> Option<&'static str> {
+    match (method, path) {
+        (Method::Get, "/status") => Some("GetStatus"),
+        (Method::Post, "/update") => Some("Update"),
+        (Method::Post, "/update/cancel") => Some("CancelUpdate"),
+        (Method::Post, "/banks/other/format") => Some("FormatOtherBank"),
+        (Method::Post, "/banks/other/copy-config") => Some("CopyConfig"),
+        (Method::Post, "/banks/desired") => Some("SetDesiredBank"),
+        (Method::Post, "/banks/ok") => Some("SetBankOk"),
+        (Method::Post, "/health-checks") => Some("RunHealthChecks"),
+        (Method::Get, "/uboot-env") => Some("GetUBootEnv"),
+        (Method::Post, "/uboot-env") => Some("SetUBootEnv"),
+        _ => None,
+    }
+}
+
+/// Build the command from its name and the JSON object in the request body
+fn parse_command(name: &str, request: &mut Request) -> Result<Command, String> {
+    let mut body = String::new();
+    request.as_reader().take(MAX_BODY_SIZE).read_to_string(&mut body)
+        .map_err(|e| format!("Cannot read request body: {}", e))?;
+
+    let mut fields = if body.trim().is_empty() {
+        serde_json::Map::new()
+    }
+    else {
+        match serde_json::from_str(&body) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            Ok(_) => return Err("Request body must be a JSON object".to_owned()),
+            Err(e) => return Err(format!("Invalid JSON in request body: {}", e)),
+        }
+    };
+
+    fields.insert("command".to_owned(), serde_json::Value::from(name));
+    serde_json::from_value(serde_json::Value::Object(fields)).map_err(|e| e.to_string())
+}
+
+fn json_response(code: u16, body: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body)
+        .with_status_code(code)
+        .with_header(Header::from_bytes("Content-Type", "application/json").expect("valid header"))
+}
+
+fn error_response(code: u16, detail: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    let body = serde_json::json!({ "status": "Error", "detail": detail });
+    json_response(code, body.to_string())
+}
+
+fn handle_request(ctx: &zmq::Context, mut request: Request) {
+    let response = match route(request.method(), path(&request)) {
+        None => error_response(404, format!("No route for {} {}", request.method(), path(&request))),
+        Some(name) => match parse_command(name, &mut request) {
+            Err(e) => error_response(400, e),
+            Ok(command) => match control::request(ctx, control::INPROC_ENDPOINT, bearer_token(&request).as_deref(), command, COMMAND_TIMEOUT) {
+                Err(e) => error_response(503, e.to_string()),
+                Ok(reply) => {
+                    let code = match serde_json::from_str::<serde_json::Value>(&reply) {
+                        Ok(r) if r["status"] == "Unauthorized" => 403,
+                        Ok(r) if !control::is_failure(&r) => 200,
+                        _ => 500,
+                    };
+                    json_response(code, reply)
+                },
+            },
+        },
+    };
+
+    if let Err(e) = request.respond(response) {
+        eprintln!("Failed to send HTTP response: {}", e);
+    }
+}
+
+/// Send the Status as a Server-Sent Event whenever it changes, until the client disconnects.
+/// The response is written directly to the connection, because tiny_http buffers chunked responses.
+fn stream_events(ctx: &zmq::Context, request: Request) {
+    let token = bearer_token(&request);
+
+    // Refuse the stream right away rather than sending Unauthorized events
+    if let Ok(reply) = control::request(ctx, control::INPROC_ENDPOINT, token.as_deref(), Command::GetStatus, COMMAND_TIMEOUT) {
+        if reply.contains(r#""status":"Unauthorized""#) {
+            if let Err(e) = request.respond(json_response(403, reply)) {
+                eprintln!("Failed to send HTTP response: {}", e);
+            }
+            return;
+        }
+    }
+
+    let mut writer = request.into_writer();
+
+    let header = "HTTP/1.1 200 OK\r\n\
+                  Content-Type: text/event-stream\r\n\
+                  Cache-Control: no-cache\r\n\
+                  Connection: close\r\n\r\n";
+    if writer.write_all(header.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+
+    let mut last_status = String::new();
+    let mut unchanged = 0;
+
+    loop {
+        let event = match control::request(ctx, control::INPROC_ENDPOINT, token.as_deref(), Command::GetStatus, COMMAND_TIMEOUT) {
+            Ok(status) if status != last_status => {
+                let event = format!("event: status\ndata: {}\n\n", status);
+                last_status = status;
+                Some(event)
+            },
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("Events stream cannot get status: {}", e);
+                None
+            },
+        };
+
+        let event = match event {
+            Some(event) => {
+                unchanged = 0;
+                event
+            },
+            None if unchanged >= EVENTS_KEEPALIVE => {
+                unchanged = 0;
+                ": keepalive\n\n".to_owned()
+            },
+            None => {
+                unchanged += 1;
+                String::new()
+            },
+        };
+
+        if !event.is_empty() && writer.write_all(event.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+
+        std::thread::sleep(EVENTS_INTERVAL);
+    }
+}
@@ -1,5 +1,6 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{spawn, JoinHandle};
 use std::time::{Duration, Instant};
@@ -9,14 +10,36 @@ use std::io::{Read, Write};
 use chrono::prelude::*;
 use base64::prelude::*;
 use serde::{Serialize, Deserialize};
-use zstd::stream::Decoder;
 use tar::Archive;
+use minisign_verify::StreamVerifier;
+use sha2::{Digest, Sha256};
 
 mod banks;
 use banks::{Bank, MountGuard};
 mod ubootenv;
-
-#[derive(Debug, Deserialize)]
+mod fw_env;
+mod signature;
+mod manifest;
+mod config;
+mod health;
+mod bootconfirm;
+mod cli;
+mod control;
+mod http;
+mod events;
+mod auth;
+mod download;
+mod source;
+mod compression;
+mod rawimage;
+mod delta;
+mod safety;
+use config::{Config, PartitionLayout};
+use events::{Event, Publisher};
+
+use clap::Parser;
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "command")]
 enum Command {
     /// Return current status and progess
@@ -24,7 +47,9 @@ enum Command {
 
     /// Format other bank, download and extract firmware, and copy config over
     Update {
-        /// URL from where to get the firmware (.tar.zstd)
+        /// URL from where to get the firmware (.tar.zstd, .tar.xz, .tar.gz or .tar, or a raw ext4
+        /// image compressed the same way): http(s)://, file://, a local path, or `removable:` for
+        /// the first image on a USB stick. The tar archive can also be a delta image, see delta.rs
         from_url: String,
 
         /// Username for HTTP Basic Auth
@@ -32,8 +57,16 @@ enum Command {
 
         /// Password for HTTP Basic Auth
         password: Option<String>,
+
+        /// Expected SHA-256 of the image, hex-encoded. If not given, it is taken from
+        /// the manifest next to the image, if there is one. The manifest is also used to
+        /// reject images that are not compatible with the device, see manifest.rs
+        sha256: Option<String>,
     },
 
+    /// Stop the ongoing update at the next file boundary
+    CancelUpdate,
+
     /// Format other bank
     FormatOtherBank,
 
@@ -45,108 +78,356 @@ enum Command {
         bank: Bank,
     },
 
-    /// Write the last_ok_bank to current bank
+    /// Write the last_ok_bank to current bank, if the required health checks pass
     SetBankOk,
+
+    /// Run the health checks and return the report
+    RunHealthChecks,
+
+    /// Return the whole U-Boot env
+    GetUBootEnv,
+
+    /// Set U-Boot env variables, or unset those whose value is null.
+    /// Variables that select the bank cannot be changed this way.
+    SetUBootEnv {
+        variables: BTreeMap<String, Option<String>>,
+    },
+}
+
+/// A command as received on the control socket, with the token that authorises it
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+
+    #[serde(flatten)]
+    command: Command,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(tag = "status")]
+#[allow(clippy::large_enum_variant)]
 enum CommandResult {
     Error { detail: String },
-    Status { banks: DetectedBankInfo, progress: Option<i32> },
+    /// The token is missing, invalid, or its role does not allow the command
+    Unauthorized { detail: String },
+    Status { banks: DetectedBankInfo, progress: Option<Progress>, last_update: Option<LastUpdate> },
+    UBootEnv { variables: BTreeMap<String, String> },
+    HealthChecks { report: health::HealthReport },
+    /// SetBankOk was refused
+    HealthChecksFailed { detail: String, report: health::HealthReport },
     Ok { detail: String }
 }
 
 const VERSION_FILENAME : &'static str = "image_built_at.txt";
 const EXTRACTED_AT_FILENAME : &'static str = "extracted_at.txt";
+// Present in a bank that must not be booted, contains the reason
+const INVALID_FILENAME : &str = "bank_invalid.txt";
+const UPDATE_IN_PROGRESS : &str = "Update in progress";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+enum UpdateOutcome {
+    Success,
+    Failed,
+    Cancelled,
+}
+
+/// Result of the last update that ran since the daemon started
+#[derive(Debug, Clone, Serialize)]
+struct LastUpdate {
+    result: UpdateOutcome,
+    error: Option<String>,
+    from_url: String,
+    started_at: String,
+    finished_at: String,
+    bytes_downloaded: u64,
+    files_extracted: usize,
+}
+
+/// Returned by the update thread. The other bank is still mounted if the update succeeded.
+struct FinishedUpdate {
+    mount_guard: Option<MountGuard>,
+    last_update: LastUpdate,
+}
 
-type UpdateResult = JoinHandle<Result<MountGuard, String>>;
+/// Returned inside the update thread when a CancelUpdate was received
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Update cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+fn check_cancelled(cancel_requested: &AtomicBool) -> Result<(), Box<dyn std::error::Error>> {
+    if cancel_requested.load(Ordering::Relaxed) {
+        Err(Box::new(Cancelled))
+    }
+    else {
+        Ok(())
+    }
+}
+
+type UpdateResult = JoinHandle<FinishedUpdate>;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+enum UpdatePhase {
+    Connecting,
+    Formatting,
+    Mounting,
+    /// Downloading and extracting happen together, as the image is streamed
+    Extracting,
+    /// Downloading a raw filesystem image and writing it to the other bank
+    Writing,
+    /// Checking and growing the raw filesystem image to the partition size
+    Resizing,
+    /// Copying the files a delta image does not contain from the running bank
+    CopyingUnchanged,
+    CopyingConfig,
+    Finalising,
+}
+
+/// Percentage of `total` done, without overflowing on large images
+fn percent(done: u64, total: u64) -> Option<i32> {
+    done.checked_mul(100)
+        .and_then(|d| d.checked_div(total))
+        .and_then(|p| p.try_into().ok())
+}
+
+/// Progress of the ongoing update, as reported in GetStatus
+#[derive(Debug, Clone, Serialize)]
+struct Progress {
+    phase: UpdatePhase,
+    /// Only known if the server sent a Content-Length
+    percent: Option<i32>,
+    bytes_transferred: u64,
+    total_bytes: Option<u64>,
+    files_extracted: usize,
+    bytes_per_second: Option<u64>,
+    eta_seconds: Option<u64>,
+}
 
-// State is either None: no update running; or Some(percent) when an update is running
+// State is either None: no update running; or Some(progress) when an update is running.
+// The counters are updated from the download loop without locking.
+// Phase changes and throughput updates are also published as events.
 #[derive(Clone)]
 struct ProgressState {
-    pub progress : Arc<Mutex<Option<i32>>>
+    pub progress : Arc<Mutex<Option<Progress>>>,
+    pub bytes_downloaded : Arc<AtomicU64>,
+    pub files_extracted : Arc<AtomicUsize>,
+    publisher : Publisher,
 }
 
 impl ProgressState {
-    pub fn new() -> Self {
-        ProgressState { progress : Arc::new(Mutex::new(None)) }
+    pub fn new(publisher: Publisher) -> Self {
+        ProgressState {
+            progress : Arc::new(Mutex::new(None)),
+            bytes_downloaded : Arc::new(AtomicU64::new(0)),
+            files_extracted : Arc::new(AtomicUsize::new(0)),
+            publisher,
+        }
+    }
+
+    pub fn set_phase(&self, phase: UpdatePhase) {
+        let mut progress = self.progress.lock().expect("lock mutex");
+        match progress.as_mut() {
+            Some(p) => p.phase = phase,
+            None => {
+                *progress = Some(Progress {
+                    phase,
+                    percent: None,
+                    bytes_transferred: 0,
+                    total_bytes: None,
+                    files_extracted: 0,
+                    bytes_per_second: None,
+                    eta_seconds: None,
+                });
+            },
+        }
+
+        self.publisher.publish(Event::UpdatePhase{ phase });
     }
 
-    pub fn update_progress(&self, percent: i32) -> ()
-    {
-        *(self.progress.lock().expect("lock mutex")) = Some(percent);
+    pub fn set_total_bytes(&self, total_bytes: Option<u64>) {
+        if let Some(p) = self.progress.lock().expect("lock mutex").as_mut() {
+            p.total_bytes = total_bytes;
+        }
+    }
+
+    pub fn update_throughput(&self, bytes_per_second: u64) {
+        let bytes_transferred = self.bytes_downloaded.load(Ordering::Relaxed);
+        if let Some(p) = self.progress.lock().expect("lock mutex").as_mut() {
+            p.bytes_per_second = Some(bytes_per_second);
+            p.eta_seconds = match p.total_bytes {
+                Some(total) if bytes_per_second > 0 => Some(total.saturating_sub(bytes_transferred) / bytes_per_second),
+                _ => None,
+            };
+        }
+
+        if let Some(progress) = self.snapshot() {
+            self.publisher.publish(Event::UpdateProgress{ progress });
+        }
+    }
+
+    /// Current progress with up-to-date counters
+    pub fn snapshot(&self) -> Option<Progress> {
+        let mut progress = self.progress.lock().expect("lock mutex").clone()?;
+        progress.bytes_transferred = self.bytes_downloaded.load(Ordering::Relaxed);
+        progress.files_extracted = self.files_extracted.load(Ordering::Relaxed);
+        progress.percent = match progress.total_bytes {
+            Some(total) => percent(progress.bytes_transferred, total),
+            None => None,
+        };
+        Some(progress)
+    }
+
+    pub fn clear(&self) {
+        *(self.progress.lock().expect("lock mutex")) = None;
     }
 }
 
 struct StateMachine {
+    config: Config,
     progress_state: ProgressState,
     join_handle: Option<UpdateResult>,
+    cancel_requested: Arc<AtomicBool>,
+    last_update: Option<LastUpdate>,
+    boot_confirmation: Option<JoinHandle<()>>,
     bank_info_cache: DetectedBankInfo,
+    publisher: Publisher,
 }
 
 impl StateMachine {
-    pub fn new() -> Self {
-        let current_bank_info = banks::mount_other_bank()
+    pub fn new(config: Config, publisher: Publisher) -> Self {
+        let current_bank_info = banks::mount_other_bank(&config.partitions)
             .and_then(|mg| detect_bank_info(&mg))
             .or_else(|e| {
                 eprintln!("Could not mount other bank: {}", e);
 
-                banks::detect()
-                    .and_then(|bank|
+                banks::detect_sources(&config.partitions)
+                    .and_then(|detection|
                         Ok(DetectedBankInfo {
-                            our_bank: bank,
+                            our_bank: detection.bank,
+                            our_bank_inconsistencies: detection.inconsistencies,
                             desired_bank: None,
                             last_ok_bank: None,
                             last_tried_bank: None,
+                            boot_counter: None,
                             our_version: None,
                             our_extract_time: None,
                             other_version: None,
                             other_extract_time: None,
+                            // Also keeps SetDesiredBank from selecting a bank without filesystem
+                            other_invalid_reason: Some(format!("Cannot mount: {}", e)),
                         }))
                 })
             .unwrap();
 
+        let boot_confirmation = bootconfirm::spawn_if_needed(
+            &config.boot_confirmation,
+            &config.health_checks,
+            current_bank_info.our_bank,
+            current_bank_info.last_ok_bank,
+            &publisher);
+
         StateMachine {
-            progress_state : ProgressState::new(),
+            config,
+            progress_state : ProgressState::new(publisher.clone()),
             join_handle : None,
+            cancel_requested : Arc::new(AtomicBool::new(false)),
+            last_update : None,
+            boot_confirmation,
             bank_info_cache: current_bank_info,
+            publisher,
         }
     }
 
-    pub fn handle_command(&mut self, command: Command) -> CommandResult {
-        match command {
-            Command::GetStatus => {
-                self.join_handle = match self.join_handle.take() {
-                    Some(j) if j.is_finished() => {
-                        match j.join().expect("thread join") {
-                            Ok(mg) => {
-                                self.bank_info_cache = detect_bank_info(&mg).expect("detect bank");
-                                // And dropping the mountguard will unmount the partition now
-                            },
-                            Err(e) => {
+    /// Join the boot confirmation and update threads once they finished, and take over
+    /// what they changed in the banks
+    fn reap_finished_threads(&mut self) {
+        if self.boot_confirmation.as_ref().is_some_and(|j| j.is_finished()) {
+            if let Some(j) = self.boot_confirmation.take() {
+                j.join().expect("thread join");
+            }
+            self.refresh_bank_info();
+        }
+
+        self.join_handle = match self.join_handle.take() {
+            Some(j) if j.is_finished() => {
+                let finished = j.join().expect("thread join");
+                match finished.mount_guard {
+                    Some(mg) => {
+                        self.bank_info_cache = detect_bank_info(&mg).expect("detect bank");
+                        // And dropping the mountguard will unmount the partition now
+                    },
+                    None => {
+                        let reason = match &finished.last_update.error {
+                            Some(e) => {
                                 eprintln!("Update thread failed with {}", e);
+                                format!("Update failed: {}", e)
+                            },
+                            None => {
+                                eprintln!("Update thread was cancelled");
+                                "Update cancelled".to_owned()
                             },
                         };
 
-                        *(self.progress_state.progress.lock().expect("lock progress state")) = None;
-
-                        None
+                        // Refresh what the thread left in the other bank, if it can be mounted at all.
+                        // A raw image update leaves no filesystem, and the cache still says UPDATE_IN_PROGRESS.
+                        match banks::mount_other_bank(&self.config.partitions).and_then(|mg| detect_bank_info(&mg)) {
+                            Ok(mut info) => {
+                                // The thread did not get to replace the marker with its error
+                                if info.other_invalid_reason.as_deref() == Some(UPDATE_IN_PROGRESS) {
+                                    info.other_invalid_reason = Some(reason);
+                                }
+                                self.bank_info_cache = info;
+                            },
+                            Err(e) => {
+                                eprintln!("Could not mount other bank: {}", e);
+                                self.bank_info_cache.other_invalid_reason = Some(format!("{}, cannot mount: {}", reason, e));
+                            },
+                        }
                     },
-                    x => x,
-                };
+                }
+                self.last_update = Some(finished.last_update);
 
-                let progress = *(self.progress_state.progress.lock().expect("lock progress state"));
-                CommandResult::Status{ banks: self.bank_info_cache.clone(), progress }
+                self.progress_state.clear();
+
+                None
             },
-            Command::Update { from_url, username, password } => {
+            x => x,
+        };
+    }
+
+    /// While the boot is not confirmed, the other bank is the one we roll back to, and must stay intact
+    fn boot_confirmation_pending(&self) -> Option<String> {
+        self.boot_confirmation.as_ref()
+            .map(|_| "Boot confirmation pending, the other bank may still be needed for a rollback".to_owned())
+    }
+
+    pub fn handle_command(&mut self, command: Command) -> CommandResult {
+        // So that no command sees an update as ongoing once it finished
+        self.reap_finished_threads();
+
+        match command {
+            Command::GetStatus => {
+                let progress = self.progress_state.snapshot();
+                CommandResult::Status{ banks: self.bank_info_cache.clone(), progress, last_update: self.last_update.clone() }
+            },
+            Command::Update { from_url, username, password, sha256 } => {
                 if self.join_handle.is_some() {
                     return CommandResult::Error{ detail: "update already ongoing".to_owned() };
                 }
+                if let Some(detail) = self.boot_confirmation_pending() {
+                    return CommandResult::Error{ detail };
+                }
 
                 let r = match (username, password) {
-                    (None, None) => self.update(&from_url, None),
-                    (Some(u), Some(p)) => self.update(&from_url, Some(Credentials { username: u, password: p })),
+                    (None, None) => self.update(&from_url, None, sha256),
+                    (Some(u), Some(p)) => self.update(&from_url, Some(Credentials { username: u, password: p }), sha256),
                     _ => Err("Specify both username and password, or neither".to_owned().into())
                 };
 
@@ -158,12 +439,23 @@ impl StateMachine {
                     Err(e) => CommandResult::Error{ detail : e.to_string() },
                 }
             },
+            Command::CancelUpdate => {
+                match &self.join_handle {
+                    Some(j) if !j.is_finished() => {
+                        self.cancel_requested.store(true, Ordering::Relaxed);
+                        CommandResult::Ok{ detail : "Cancelling update".to_owned() }
+                    },
+                    _ => CommandResult::Error{ detail : "No update ongoing".to_owned() },
+                }
+            },
             Command::FormatOtherBank => {
-                match banks::format_other_bank() {
+                if let Some(detail) = self.boot_confirmation_pending() {
+                    return CommandResult::Error{ detail };
+                }
+
+                match banks::format_other_bank(&self.config.partitions) {
                     Ok(()) => {
-                        self.bank_info_cache = banks::mount_other_bank()
-                            .and_then(|mg| detect_bank_info(&mg))
-                            .unwrap();
+                        self.refresh_bank_info();
 
                         CommandResult::Ok{ detail : "Other bank formatted".to_owned() }
                     },
@@ -171,17 +463,26 @@ impl StateMachine {
                 }
             },
             Command::CopyConfig => {
-                match copy_config() {
+                match copy_config(&self.config.partitions) {
                     Ok(b) => CommandResult::Ok{ detail : format!("Config copied to bank {}", b) },
                     Err(e) => CommandResult::Error{ detail : e.to_string() },
                 }
             },
             Command::SetDesiredBank { bank } => {
-                match ubootenv::set_uboot_bank(ubootenv::UBootBankVariable::Desired, bank) {
-                    Ok(()) => {
-                        self.bank_info_cache = banks::mount_other_bank()
-                            .and_then(|mg| detect_bank_info(&mg))
-                            .unwrap();
+                if bank != self.bank_info_cache.our_bank {
+                    if self.join_handle.is_some() {
+                        return CommandResult::Error{ detail: format!("Bank {} is being updated", bank) };
+                    }
+
+                    if let Some(reason) = &self.bank_info_cache.other_invalid_reason {
+                        return CommandResult::Error{ detail: format!("Bank {} is invalid: {}", bank, reason) };
+                    }
+                }
+
+                match ubootenv::set_desired_bank(bank, self.bank_info_cache.our_bank, self.config.boot_counter.bootlimit) {
+                    Ok(variables) => {
+                        self.publisher.publish(Event::UBootEnvChanged{ reason: "SetDesiredBank".to_owned(), variables });
+                        self.refresh_bank_info();
 
                         CommandResult::Ok{ detail : format!("Configured to boot bank {}", bank) }
                     },
@@ -189,100 +490,255 @@ impl StateMachine {
                 }
             },
             Command::SetBankOk => {
-                match ubootenv::set_uboot_bank(ubootenv::UBootBankVariable::LastOk, self.bank_info_cache.our_bank) {
-                    Ok(()) => {
-                        self.bank_info_cache = banks::mount_other_bank()
-                            .and_then(|mg| detect_bank_info(&mg))
-                            .unwrap();
+                let report = health::run_checks(&self.config.health_checks);
+                if !report.passed {
+                    return CommandResult::HealthChecksFailed{ detail : format!("Health checks failed: {}", report.failures()), report };
+                }
+
+                match ubootenv::mark_bank_ok(self.bank_info_cache.our_bank) {
+                    Ok(variables) => {
+                        self.publisher.publish(Event::UBootEnvChanged{ reason: "SetBankOk".to_owned(), variables });
+                        self.refresh_bank_info();
 
                         CommandResult::Ok{ detail : format!("Saved last_bank_ok={}", self.bank_info_cache.our_bank) }
                     },
                     Err(e) => CommandResult::Error{ detail : e.to_string() },
                 }
             },
+            Command::RunHealthChecks => {
+                CommandResult::HealthChecks{ report: health::run_checks(&self.config.health_checks) }
+            },
+            Command::GetUBootEnv => {
+                match ubootenv::get_uboot_env() {
+                    Ok(variables) => CommandResult::UBootEnv{ variables },
+                    Err(e) => CommandResult::Error{ detail : e.to_string() },
+                }
+            },
+            Command::SetUBootEnv { variables } => {
+                let protected : Vec<&str> = variables.keys()
+                    .map(|name| name.as_str())
+                    .filter(|name| ubootenv::PROTECTED_VARS.contains(name))
+                    .collect();
+                if !protected.is_empty() {
+                    return CommandResult::Error{ detail : format!("Refusing to change protected variables {}", protected.join(", ")) };
+                }
+
+                let mut transaction = ubootenv::Transaction::new();
+                for (name, value) in &variables {
+                    transaction = match value {
+                        Some(v) => transaction.set(name, v),
+                        None => transaction.unset(name),
+                    };
+                }
+
+                match transaction.commit() {
+                    Ok(variables) => {
+                        let detail = format!("Changed {} U-Boot env variables", variables.len());
+                        self.publisher.publish(Event::UBootEnvChanged{ reason: "SetUBootEnv".to_owned(), variables });
+                        CommandResult::Ok{ detail }
+                    },
+                    Err(e) => CommandResult::Error{ detail : e.to_string() },
+                }
+            },
         }
     }
 
-    fn update(&mut self, url: &str, creds: Option<Credentials>) -> Result<UpdateResult, Box<dyn std::error::Error>> {
-        eprintln!("Setup Firmware Update GET request to {}", url);
-        let mut request_builder = ureq::get(url)
-            .timeout(std::time::Duration::from_secs(3600*6));
-
-        if let Some(c) = creds {
-            eprintln!("Add username {} HTTP Basic Auth", c.username);
-            let auth_header = format!(
-                "Basic {}",
-                BASE64_STANDARD.encode(&format!("{}:{}", c.username, c.password))
-            );
+    fn refresh_bank_info(&mut self) {
+        if self.join_handle.is_some() {
+            // The update thread has the other bank mounted and is writing to it
+            eprintln!("Not refreshing the bank info during an update");
+            return;
+        }
 
-            request_builder = request_builder.set("Authorization", &auth_header);
+        match banks::mount_other_bank(&self.config.partitions).and_then(|mg| detect_bank_info(&mg)) {
+            Ok(info) => self.bank_info_cache = info,
+            Err(e) => eprintln!("Could not mount other bank: {}", e),
         }
+    }
 
-        eprintln!("Connecting");
-        let response = request_builder.call()?;
-        let content_length_kb : Option<usize> = response.header("content-length")
-            .and_then(|v| usize::from_str_radix(v, 10).ok())
-            .map(|v| v / 1024);
+    fn update(&mut self, url: &str, creds: Option<Credentials>, sha256: Option<String>) -> Result<UpdateResult, Box<dyn std::error::Error>> {
+        let sha256 = match sha256 {
+            Some(digest) => Some(manifest::parse_sha256(&digest)?),
+            None => None,
+        };
+        let public_key = signature::load_public_key(self.config.signature.allow_unsigned)?;
+        let source = source::Source::resolve(url, creds, &self.config.partitions)?;
 
         self.bank_info_cache.other_extract_time = None;
         self.bank_info_cache.other_version = None;
-
+        self.bank_info_cache.other_invalid_reason = Some(UPDATE_IN_PROGRESS.to_owned());
+
+        self.cancel_requested.store(false, Ordering::Relaxed);
+        self.progress_state.bytes_downloaded.store(0, Ordering::Relaxed);
+        self.progress_state.files_extracted.store(0, Ordering::Relaxed);
+        self.progress_state.set_phase(UpdatePhase::Connecting);
+
+        let from_url = url.to_owned();
+        let started_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let layout = self.config.partitions.clone();
+        let device = self.config.device.clone();
+        let download_settings = self.config.download.clone();
         let progress_state = self.progress_state.clone();
+        let counters = self.progress_state.clone();
+        let cancel_requested = self.cancel_requested.clone();
+        let cancelled = self.cancel_requested.clone();
+        let publisher = self.publisher.clone();
+        let env_publisher = self.publisher.clone();
         let thread_handle = spawn(move || {
             let f = move || -> Result<MountGuard, Box<dyn std::error::Error>> {
-                progress_state.update_progress(0);
+                eprintln!("Fetch image manifest from {}", manifest::manifest_url(&source.location()));
+                let manifest = match source.fetch_sidecar(manifest::manifest_url, &download_settings, &cancel_requested)? {
+                    Some(contents) => Some(manifest::Manifest::parse(&contents)?),
+                    None => {
+                        eprintln!("No manifest found, the image compatibility will not be checked");
+                        None
+                    },
+                };
 
-                eprintln!("Format other bank");
-                banks::format_other_bank()?;
+                // Before anything is written to the other bank
+                if let Some(manifest) = &manifest {
+                    manifest.check_compatible(&device, banks::other_bank_size(&layout)?)?;
+                }
 
-                eprintln!("Detect and mount other bank");
-                let mount_guard = banks::mount_other_bank()?;
-                // Dropping the mount_guard unmounts the other bank
+                let expected_sha256 = match (sha256, manifest) {
+                    (Some(digest), _) => Some(digest),
+                    (None, Some(manifest)) => Some(manifest::parse_sha256(&manifest.sha256)?),
+                    (None, None) => {
+                        eprintln!("No digest given, the image digest will not be checked");
+                        None
+                    },
+                };
 
-                let other_bank_root = mount_guard.guard.target_path();
+                let signature = match public_key {
+                    Some(_) => {
+                        let signature_url = signature::signature_url(&source.location());
+                        eprintln!("Fetch image signature from {}", signature_url);
+                        let signature = source.fetch_sidecar(signature::signature_url, &download_settings, &cancel_requested)?
+                            .ok_or(format!("No image signature at {}", signature_url))?;
+                        Some(signature::decode_signature(&signature)?)
+                    },
+                    None => {
+                        eprintln!("No public key in {}, the image will not be verified", signature::PUBLIC_KEY_PATH);
+                        None
+                    },
+                };
 
-                let mut reader = ReadWrapper::new(response.into_reader());
-                let kb_counter = reader.get_kilobyte_count();
+                eprintln!("Opening {}", source.location());
+                let (image, size) = source.open(&download_settings, cancel_requested.clone())?;
+                progress_state.set_total_bytes(size);
 
-                eprintln!("Create zstd decoder");
-                let decoder = Decoder::new(&mut reader)?;
-                let mut tar_archive = Archive::new(decoder);
+                let mut reader = ReadWrapper::new(image, progress_state.bytes_downloaded.clone());
+                if let (Some(pk), Some(sig)) = (&public_key, &signature) {
+                    reader.set_verifier(pk.verify_stream(sig)?);
+                }
+                if let Some(digest) = expected_sha256 {
+                    reader.set_expected_sha256(digest);
+                }
+                let mut printer = ProgressPrinter::new(reader.get_byte_count(), size, progress_state.clone());
 
-                eprintln!("Extract files");
-                let start_time = Instant::now();
-                let print_interval = Duration::from_secs(1);
-                let mut next_print_time = start_time + print_interval;
+                check_cancelled(&cancel_requested)?;
+                let installed = {
+                    let mut decoder = compression::decoder(&mut reader, &source.location())?;
 
-                match content_length_kb {
-                    Some(cl) =>
-                        eprintln!("{}% ({}/{})  {} files extracted                 ", 0, 0, cl, 0),
-                    None =>
-                        eprintln!("Content-Length unknown, cannot show progress"),
-                }
+                    // Tell a raw filesystem image from a tar archive
+                    let mut head = Vec::new();
+                    (&mut decoder).take(rawimage::HEAD_SIZE).read_to_end(&mut head)?;
 
-                let mut file_count = 0;
-                for entry in tar_archive.entries()? {
-                    let mut entry = entry?;
-                    if !entry.unpack_in(&other_bank_root)? {
-                        eprintln!("Did not unpack {}", entry.path()?.to_string_lossy());
+                    if rawimage::is_ext4(&head) {
+                        keep_booting_our_bank(&layout, &env_publisher)?;
+                        progress_state.set_phase(UpdatePhase::Writing);
+                        let bank = rawimage::write_other_bank(&layout, &mut decoder, &mut printer, &cancel_requested)?;
+                        Installed::RawImage { bank, head }
                     }
-                    file_count += 1;
+                    else {
+                        let mut archive = Archive::new(std::io::Cursor::new(head).chain(&mut decoder));
+                        let mut entries = archive.entries()?;
+
+                        // A delta image starts with its manifest, and is rejected before formatting
+                        // if we do not run the image it was made against
+                        let mut first_entry = entries.next().transpose()?;
+                        let delta = match &mut first_entry {
+                            Some(entry) => delta::read_manifest(entry)?,
+                            None => None,
+                        };
+                        if let Some(manifest) = &delta {
+                            delta::check_base(manifest, Path::new(delta::RUNNING_ROOT))?;
+                            first_entry = None;
+                        }
 
-                    if let Some(cl) = content_length_kb {
-                        if next_print_time < Instant::now() {
-                            next_print_time += print_interval;
+                        keep_booting_our_bank(&layout, &env_publisher)?;
+                        progress_state.set_phase(UpdatePhase::Formatting);
+                        eprintln!("Format other bank");
+                        banks::format_other_bank(&layout)?;
 
-                            let kb_transferred = kb_counter.load(Ordering::Relaxed);
-                            let progress_percent = kb_transferred * 100 / cl;
-                            if let Ok(p) = progress_percent.try_into() {
-                                progress_state.update_progress(p);
-                            }
+                        progress_state.set_phase(UpdatePhase::Mounting);
+                        eprintln!("Detect and mount other bank");
+                        let mount_guard = banks::mount_other_bank(&layout)?;
+                        // Dropping the mount_guard unmounts the other bank
+
+                        let other_bank_root = mount_guard.guard.target_path();
+
+                        // The bank stays invalid until the image is fully extracted and verified,
+                        // so that an interrupted update cannot be booted
+                        mark_bank_invalid(other_bank_root, UPDATE_IN_PROGRESS)?;
 
-                            eprintln!("{}% ({}/{})  {} files extracted",
-                            progress_percent, kb_transferred, cl, file_count);
+                        progress_state.set_phase(UpdatePhase::Extracting);
+                        let entries = first_entry.map(Ok).into_iter().chain(entries);
+                        match extract(entries, other_bank_root, &mut printer, &cancel_requested) {
+                            Ok(file_count) => Installed::Tar { mount_guard, file_count, delta },
+                            Err(e) => {
+                                mark_bank_invalid(other_bank_root, &e.to_string())?;
+                                return Err(e);
+                            },
                         }
                     }
+                };
+
+                // The end of the tar archive or filesystem can come before the end of the stream,
+                // the digest and signature cover everything
+                let verified = if reader.verifies_stream() {
+                    std::io::copy(&mut reader, &mut std::io::sink())
+                        .map_err(|e| e.into())
+                        .and_then(|_| reader.finalize_verification())
                 }
+                else {
+                    Ok(())
+                };
+
+                let mount_guard = match installed {
+                    Installed::Tar { mount_guard, file_count, delta } => {
+                        if let Err(e) = verified {
+                            mark_bank_invalid(mount_guard.guard.target_path(), &e.to_string())?;
+                            return Err(e);
+                        }
+                        eprintln!("{} files extracted", file_count);
+
+                        if let Some(manifest) = delta {
+                            progress_state.set_phase(UpdatePhase::CopyingUnchanged);
+                            let other_bank_root = mount_guard.guard.target_path();
+                            let copied = banks::rewritten_files()
+                                .and_then(|rewritten| delta::copy_unchanged(&manifest, Path::new(delta::RUNNING_ROOT), other_bank_root, &rewritten, &mut printer, file_count, &cancel_requested));
+                            if let Err(e) = copied {
+                                mark_bank_invalid(other_bank_root, &e.to_string())?;
+                                return Err(e);
+                            }
+                        }
+                        mount_guard
+                    },
+                    Installed::RawImage { bank, head } => {
+                        // Without its head, the bank contains no filesystem that could be booted
+                        verified?;
+
+                        progress_state.set_phase(UpdatePhase::Resizing);
+                        rawimage::finish(&layout, bank, &head)?;
+
+                        progress_state.set_phase(UpdatePhase::Mounting);
+                        let mount_guard = banks::mount_other_bank(&layout)?;
+                        mark_bank_invalid(mount_guard.guard.target_path(), UPDATE_IN_PROGRESS)?;
+                        mount_guard
+                    },
+                };
+                let other_bank_root = mount_guard.guard.target_path();
 
                 let extract_completion_time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
                 eprintln!("Mark the extraction as completed at {}", extract_completion_time);
@@ -291,73 +747,273 @@ impl StateMachine {
                     let extracted_at_path = other_bank_root.join(EXTRACTED_AT_FILENAME);
 
                     let mut file = File::options()
-                        .create_new(true)
+                        .create(true)
                         .write(true)
                         .truncate(true)
                         .open(extracted_at_path)?;
                     file.write_all(format!("{}\n", extract_completion_time).as_bytes())?;
                 }
 
-                eprintln!("{} files extracted", file_count);
-
+                progress_state.set_phase(UpdatePhase::CopyingConfig);
                 eprintln!("Bank {} mounted to {}", mount_guard.other_bank, other_bank_root.to_string_lossy());
                 banks::copy_config(&other_bank_root)?;
-                banks::render_fstab(mount_guard.other_bank, &other_bank_root.join("etc/fstab"))?;
+
+                progress_state.set_phase(UpdatePhase::Finalising);
+                banks::render_fstab(&layout, mount_guard.other_bank, &other_bank_root.join(banks::FSTAB_PATH))?;
+
+                std::fs::remove_file(other_bank_root.join(INVALID_FILENAME))?;
 
                 eprintln!("Update completed");
 
                 Ok(mount_guard)
             };
 
-            match f() {
-                Ok(mg) => Ok(mg),
-                Err(e) => Err(format!("{:?}", e)),
+            let (mount_guard, result, error) = match f() {
+                Ok(mg) => (Some(mg), UpdateOutcome::Success, None),
+                // Also when the cancellation interrupted the download with an I/O error
+                Err(e) if e.is::<Cancelled>() || cancelled.load(Ordering::Relaxed) => (None, UpdateOutcome::Cancelled, None),
+                Err(e) => (None, UpdateOutcome::Failed, Some(e.to_string())),
+            };
+
+            let last_update = LastUpdate {
+                result,
+                error,
+                from_url,
+                started_at,
+                finished_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                bytes_downloaded: counters.bytes_downloaded.load(Ordering::Relaxed),
+                files_extracted: counters.files_extracted.load(Ordering::Relaxed),
+            };
+            publisher.publish(Event::UpdateFinished{ last_update: last_update.clone() });
+
+            FinishedUpdate {
+                mount_guard,
+                last_update,
             }
         });
         Ok(thread_handle)
     }
 }
 
+/// Logs the progress of the download and updates the throughput, about once per second
+struct ProgressPrinter {
+    byte_counter : Arc<AtomicU64>,
+    content_length : Option<u64>,
+    progress_state : ProgressState,
+    last_print_time : Instant,
+    last_print_bytes : u64,
+}
+
+impl ProgressPrinter {
+    const INTERVAL : Duration = Duration::from_secs(1);
+
+    fn new(byte_counter: Arc<AtomicU64>, content_length: Option<u64>, progress_state: ProgressState) -> Self {
+        match content_length {
+            Some(cl) =>
+                eprintln!("{}% ({}/{} kB)", 0, 0, cl / 1024),
+            None =>
+                eprintln!("Content-Length unknown, cannot show progress"),
+        }
+
+        ProgressPrinter {
+            byte_counter,
+            content_length,
+            progress_state,
+            last_print_time: Instant::now(),
+            last_print_bytes: 0,
+        }
+    }
+
+    fn tick(&mut self, files_extracted: usize) {
+        let now = Instant::now();
+        if self.last_print_time + Self::INTERVAL >= now {
+            return;
+        }
+
+        let bytes_transferred = self.byte_counter.load(Ordering::Relaxed);
+        let bytes_per_second = (bytes_transferred.saturating_sub(self.last_print_bytes) as f64 / (now - self.last_print_time).as_secs_f64()) as u64;
+        self.progress_state.update_throughput(bytes_per_second);
+        self.last_print_time = now;
+        self.last_print_bytes = bytes_transferred;
+
+        match self.content_length {
+            Some(cl) =>
+                eprintln!("{}% ({}/{} kB)  {} files extracted, {} kB/s",
+                percent(bytes_transferred, cl).unwrap_or(0), bytes_transferred / 1024, cl / 1024, files_extracted, bytes_per_second / 1024),
+            None =>
+                eprintln!("{} kB  {} files extracted, {} kB/s",
+                bytes_transferred / 1024, files_extracted, bytes_per_second / 1024),
+        }
+    }
+}
+
+/// What the update wrote to the other bank, before the image is verified
+enum Installed {
+    Tar { mount_guard: MountGuard, file_count: usize, delta: Option<delta::DeltaManifest> },
+    /// The head of the image is written once it is verified, see rawimage.rs
+    RawImage { bank: Bank, head: Vec<u8> },
+}
+
+/// Extract the entries of the tar archive into the other bank. Returns the number of extracted files.
+fn extract<'a, R: Read + 'a>(entries: impl Iterator<Item = std::io::Result<tar::Entry<'a, R>>>, other_bank_root: &Path, printer: &mut ProgressPrinter, cancel_requested: &AtomicBool) -> Result<usize, Box<dyn std::error::Error>> {
+    eprintln!("Extract files");
+
+    let mut file_count = 0;
+    for entry in entries {
+        check_cancelled(cancel_requested)?;
+
+        let mut entry = entry?;
+        if !entry.unpack_in(other_bank_root)? {
+            eprintln!("Did not unpack {}", entry.path()?.to_string_lossy());
+        }
+        file_count += 1;
+        printer.progress_state.files_extracted.store(file_count, Ordering::Relaxed);
+        printer.tick(file_count);
+    }
+
+    Ok(file_count)
+}
+
+/// Before the other bank is overwritten, make sure U-Boot does not boot it, in case an earlier
+/// SetDesiredBank selected it. The operator selects it again once the update succeeded.
+fn keep_booting_our_bank(layout: &PartitionLayout, publisher: &Publisher) -> Result<(), Box<dyn std::error::Error>> {
+    let our_bank = banks::detect(layout)?;
+    let variables = ubootenv::set_desired_bank(our_bank, our_bank, None)?;
+    eprintln!("Configured to boot bank {} during the update", our_bank);
+    publisher.publish(Event::UBootEnvChanged{ reason: "Update".to_owned(), variables });
+    Ok(())
+}
+
+fn mark_bank_invalid(bank_root: &Path, reason: &str) -> std::io::Result<()> {
+    eprintln!("Mark bank invalid: {}", reason);
+    let mut file = File::options()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(bank_root.join(INVALID_FILENAME))?;
+    file.write_all(format!("{}\n", reason).as_bytes())
+}
+
+fn with_credentials(mut request_builder: ureq::Request, creds: &Option<Credentials>) -> ureq::Request {
+    if let Some(c) = creds {
+        eprintln!("Add username {} HTTP Basic Auth", c.username);
+        let auth_header = format!(
+            "Basic {}",
+            BASE64_STANDARD.encode(&format!("{}:{}", c.username, c.password))
+        );
+
+        request_builder = request_builder.set("Authorization", &auth_header);
+    }
+
+    request_builder
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut state_machine = StateMachine::new();
+    let cli = cli::Cli::parse();
 
+    let mut config = Config::load(&cli.config)?;
+    if let Some(endpoint) = cli.endpoint {
+        config.control.endpoint = endpoint;
+    }
+
+    match cli.action.unwrap_or(cli::Action::Daemon) {
+        cli::Action::Daemon => run_daemon(config),
+        cli::Action::Watch => match &config.control.events_endpoint {
+            Some(endpoint) => cli::watch(endpoint),
+            None => Err("No events_endpoint in the configuration".into()),
+        },
+        action => {
+            let command = action.into_command().expect("action is a command");
+            if !cli::send_to_daemon(&config.control.endpoint, cli.token.as_deref(), command)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+    }
+}
+
+fn run_daemon(config: Config) -> Result<(), Box<dyn std::error::Error>> {
     let ctx = zmq::Context::new();
-    let socket = ctx.socket(zmq::REP).unwrap();
-    socket.bind("tcp://127.0.0.1:5552").unwrap();
+    let socket = ctx.socket(zmq::REP)?;
+    control::bind(&socket, &config.control.endpoint, config.control.ipc_mode)?;
+
+    let publisher = match &config.control.events_endpoint {
+        Some(endpoint) => {
+            let events_socket = ctx.socket(zmq::PUB)?;
+            control::bind(&events_socket, endpoint, config.control.ipc_mode)?;
+            Publisher::start(events_socket)
+        },
+        None => Publisher::disabled(),
+    };
+
+    if config.http.enabled {
+        socket.bind(control::INPROC_ENDPOINT)?;
+        http::spawn(ctx.clone(), &config.http)?;
+    }
+
+    let tokens = config.control.tokens.clone();
+    let mut state_machine = StateMachine::new(config, publisher);
 
     let mut msg = zmq::Message::new();
     loop {
-        socket.recv(&mut msg, 0).unwrap();
-        let msgstr = msg.as_str().unwrap();
-        let response = match serde_json::from_str::<Command>(&msgstr) {
-            Ok(c) => {
-                state_machine.handle_command(c)
+        if let Err(e) = socket.recv(&mut msg, 0) {
+            eprintln!("Cannot receive command: {}", e);
+            continue;
+        }
+        // The reply can only be sent once all frames of the request are received
+        let mut multipart = false;
+        while msg.get_more() {
+            multipart = true;
+            if let Err(e) = socket.recv(&mut msg, 0) {
+                eprintln!("Cannot receive command: {}", e);
+                break;
+            }
+        }
+
+        let parsed = match msg.as_str() {
+            _ if multipart => Err("Commands must be a single frame".to_owned()),
+            Some(msgstr) => serde_json::from_str::<Request>(msgstr).map_err(|e| e.to_string()),
+            None => Err("Command is not valid UTF-8".to_owned()),
+        };
+        let response = match parsed {
+            Ok(r) => {
+                match auth::authorize(&tokens, r.token.as_deref(), &r.command) {
+                    Ok(()) => state_machine.handle_command(r.command),
+                    Err(detail) => {
+                        eprintln!("Refusing command: {}", detail);
+                        CommandResult::Unauthorized{ detail }
+                    },
+                }
             }
             Err(e) => {
-                eprintln!("Error parsing that command: {:?}", e);
-                CommandResult::Error{ detail : e.to_string() }
+                eprintln!("Error parsing that command: {}", e);
+                CommandResult::Error{ detail : e }
             }
         };
 
         let responsestr = serde_json::to_string(&response).expect("serialize to JSON");
-        socket.send(&responsestr, 0).expect("send ZMQ");
-
-        std::thread::sleep(Duration::from_millis(200));
+        if let Err(e) = socket.send(&responsestr, 0) {
+            eprintln!("Cannot send reply: {}", e);
+        }
     }
 }
 
 #[derive(Serialize, Debug, Clone)]
 struct DetectedBankInfo {
     pub our_bank : banks::Bank,
+    /// Set when /proc/self/mountinfo, /proc/cmdline and /etc/fstab disagree on our bank
+    pub our_bank_inconsistencies : Vec<String>,
     pub desired_bank : Option<banks::Bank>,
     pub last_ok_bank : Option<banks::Bank>,
     pub last_tried_bank : Option<banks::Bank>,
+    pub boot_counter : Option<ubootenv::BootCounter>,
 
     pub our_version : Option<String>,
     pub our_extract_time : Option<String>,
 
     pub other_version : Option<String>,
     pub other_extract_time : Option<String>,
+    pub other_invalid_reason : Option<String>,
 }
 
 
@@ -406,6 +1062,14 @@ fn detect_bank_info(mount_guard: &MountGuard) -> Result<DetectedBankInfo, Box<dy
     };
 
 
+    let boot_counter = match ubootenv::get_boot_counter() {
+        Ok(c) => Some(c),
+        Err(e) => {
+            eprintln!("Failed to read boot counter from u-boot env: {}", e);
+            None
+        }
+    };
+
     let our_bank = mount_guard.other_bank.other();
     let other_bank_root = mount_guard.guard.target_path();
 
@@ -415,52 +1079,95 @@ fn detect_bank_info(mount_guard: &MountGuard) -> Result<DetectedBankInfo, Box<dy
     let other_version = read_file_contents(&other_bank_root.join(VERSION_FILENAME));
     let other_extract_time = read_file_contents(&other_bank_root.join(EXTRACTED_AT_FILENAME));
 
+    let invalid_path = other_bank_root.join(INVALID_FILENAME);
+    let other_invalid_reason = if invalid_path.exists() {
+        read_file_contents(&invalid_path)
+    }
+    else {
+        None
+    };
+
     Ok(DetectedBankInfo {
         our_bank,
+        our_bank_inconsistencies: Vec::new(),
         desired_bank,
         last_tried_bank,
         last_ok_bank,
+        boot_counter,
         our_version,
         our_extract_time,
         other_version,
         other_extract_time,
+        other_invalid_reason,
     })
 }
 
-fn copy_config() -> Result<Bank, Box<dyn std::error::Error>> {
+fn copy_config(layout: &PartitionLayout) -> Result<Bank, Box<dyn std::error::Error>> {
     eprintln!("Detect and mount other bank");
-    let mount_guard = banks::mount_other_bank()?;
+    let mount_guard = banks::mount_other_bank(layout)?;
     let other_bank_root = mount_guard.guard.target_path();
     eprintln!("Bank {} mounted to {}", mount_guard.other_bank, other_bank_root.to_string_lossy());
     banks::copy_config(&other_bank_root)?;
-    banks::render_fstab(mount_guard.other_bank, &other_bank_root.join("etc/fstab"))?;
+    banks::render_fstab(layout, mount_guard.other_bank, &other_bank_root.join(banks::FSTAB_PATH))?;
     Ok(mount_guard.other_bank)
 }
 
-
+#[derive(Clone)]
 struct Credentials {
     pub username: String,
     pub password: String,
 }
 
-struct ReadWrapper {
+struct ReadWrapper<'a> {
     reader : Box<dyn Read>,
-    count : Arc<AtomicUsize>,
+    count : Arc<AtomicU64>,
+    verifier : Option<StreamVerifier<'a>>,
+    sha256 : Option<(Sha256, String)>,
 }
 
-impl ReadWrapper {
-    pub fn new(reader: Box<dyn Read>) -> Self {
-        let count = Arc::new(0.into());
-        Self{ reader, count }
+impl<'a> ReadWrapper<'a> {
+    pub fn new(reader: Box<dyn Read>, count: Arc<AtomicU64>) -> Self {
+        Self{ reader, count, verifier: None, sha256: None }
+    }
+    pub fn get_byte_count(&self) -> Arc<AtomicU64> { self.count.clone() }
+
+    pub fn set_verifier(&mut self, verifier: StreamVerifier<'a>) { self.verifier = Some(verifier); }
+
+    pub fn set_expected_sha256(&mut self, digest: String) { self.sha256 = Some((Sha256::new(), digest)); }
+
+    pub fn verifies_stream(&self) -> bool { self.verifier.is_some() || self.sha256.is_some() }
+
+    pub fn finalize_verification(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some((hasher, expected)) = self.sha256.take() {
+            let digest = format!("{:x}", hasher.finalize());
+            if digest != expected {
+                return Err(format!("Image SHA-256 mismatch: expected {}, got {}", expected, digest).into());
+            }
+            eprintln!("Image SHA-256 verified");
+        }
+
+        if let Some(v) = self.verifier.as_mut() {
+            if let Err(e) = v.finalize() {
+                return Err(format!("Image signature verification failed: {}", e).into());
+            }
+            eprintln!("Image signature verified");
+        }
+
+        Ok(())
     }
-    pub fn get_kilobyte_count(&self) -> Arc<AtomicUsize> { self.count.clone() }
 }
 
-impl Read for ReadWrapper {
+impl Read for ReadWrapper<'_> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let r = self.reader.read(buf);
         if let Ok(c) = r {
-            self.count.fetch_add(c / 1024, Ordering::Relaxed);
+            self.count.fetch_add(c as u64, Ordering::Relaxed);
+            if let Some(v) = self.verifier.as_mut() {
+                v.update(&buf[..c]);
+            }
+            if let Some((hasher, _)) = self.sha256.as_mut() {
+                hasher.update(&buf[..c]);
+            }
         }
         r
     }
@@ -0,0 +1,192 @@
+use serde::Deserialize;
+
+use crate::config::Device;
+use crate::ubootenv;
+
+/// U-Boot sets this variable to e.g. "U-Boot 2023.04 (Apr 03 2023 - 10:00:00 +0000)"
+const BOOTLOADER_VERSION_VAR : &str = "ver";
+
+/// Small JSON file published next to the image, e.g.
+/// ```json
+/// {
+///   "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+///   "version": "1.4.0",
+///   "built_at": "2024-03-01T12:00:00Z",
+///   "hardware": "dexter",
+///   "board_revisions": ["2", "3"],
+///   "min_bootloader_version": "2023.04",
+///   "required_space_bytes": 3000000000
+/// }
+/// ```
+/// Only the digest is mandatory. The image is rejected before the other bank is formatted
+/// if it does not fit the device, see `check_compatible`.
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    /// Hex-encoded SHA-256 digest of the compressed image
+    pub sha256: String,
+    pub version: Option<String>,
+    /// Same as image_built_at.txt in the image
+    pub built_at: Option<String>,
+    /// Hardware the image is built for, compared with `hardware` in the [device] config
+    pub hardware: Option<String>,
+    /// Board revisions the image supports. Empty means all of them.
+    #[serde(default)]
+    pub board_revisions: Vec<String>,
+    /// Oldest U-Boot that can boot the image
+    pub min_bootloader_version: Option<String>,
+    /// Space the installed image needs in the bank
+    pub required_space_bytes: Option<u64>,
+}
+
+pub fn manifest_url(image_url: &str) -> String {
+    format!("{}.manifest.json", image_url)
+}
+
+/// Check that the digest is 64 hex characters, and return it in lowercase
+pub fn parse_sha256(digest: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let digest = digest.trim();
+    if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(digest.to_ascii_lowercase())
+    }
+    else {
+        Err(format!("Invalid SHA-256 digest '{}'", digest).into())
+    }
+}
+
+impl Manifest {
+    pub fn parse(contents: &str) -> Result<Manifest, Box<dyn std::error::Error>> {
+        let manifest : Manifest = serde_json::from_str(contents)
+            .map_err(|e| format!("Invalid image manifest: {}", e))?;
+        parse_sha256(&manifest.sha256)?;
+
+        eprintln!("Image version {}, built at {}",
+            manifest.version.as_deref().unwrap_or("unknown"),
+            manifest.built_at.as_deref().unwrap_or("unknown"));
+        Ok(manifest)
+    }
+
+    /// Reject the image if it is not meant for this device, or does not fit in a bank of `bank_size` bytes
+    pub fn check_compatible(&self, device: &Device, bank_size: u64) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(hardware) = &self.hardware {
+            match &device.hardware {
+                Some(ours) if ours == hardware => (),
+                Some(ours) => return Err(format!("Image is for hardware {}, this is {}", hardware, ours).into()),
+                None => return Err(format!("Image is for hardware {}, but the hardware of this device is not configured", hardware).into()),
+            }
+        }
+
+        if !self.board_revisions.is_empty() {
+            match &device.board_revision {
+                Some(ours) if self.board_revisions.contains(ours) => (),
+                Some(ours) => return Err(format!("Image supports board revisions {}, this is revision {}", self.board_revisions.join(", "), ours).into()),
+                None => return Err(format!("Image supports board revisions {}, but the revision of this device is not configured", self.board_revisions.join(", ")).into()),
+            }
+        }
+
+        if let Some(minimum) = &self.min_bootloader_version {
+            let required = parse_version(minimum)
+                .ok_or(format!("Invalid min_bootloader_version '{}' in manifest", minimum))?;
+            let ours = bootloader_version(device)
+                .ok_or(format!("Image requires bootloader {}, but the version of ours is unknown", minimum))?;
+            if parse_version(&ours).is_none_or(|v| v < required) {
+                return Err(format!("Image requires bootloader {}, we have {}", minimum, ours).into());
+            }
+        }
+
+        if let Some(required) = self.required_space_bytes {
+            if required > bank_size {
+                return Err(format!("Image needs {} MB, the bank only has {} MB", required / 1024 / 1024, bank_size / 1024 / 1024).into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn bootloader_version(device: &Device) -> Option<String> {
+    if let Some(version) = &device.bootloader_version {
+        return Some(version.clone());
+    }
+
+    match ubootenv::get_uboot_env() {
+        Ok(mut variables) => variables.remove(BOOTLOADER_VERSION_VAR),
+        Err(e) => {
+            eprintln!("Failed to read bootloader version from u-boot env: {}", e);
+            None
+        },
+    }
+}
+
+/// The numbers of the first dotted version in `text`, e.g. [2023, 4] for "U-Boot 2023.04-rc2"
+fn parse_version(text: &str) -> Option<Vec<u32>> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let version : String = text[start..].chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+
+    version.split('.')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST : &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    const BANK_SIZE : u64 = 2 * 1024 * 1024 * 1024;
+
+    fn manifest(fields: &str) -> Manifest {
+        Manifest::parse(&format!(r#"{{"sha256": "{}"{}}}"#, DIGEST, fields)).unwrap()
+    }
+
+    /// With the bootloader version configured, so that the U-Boot env is not read
+    fn device(hardware: Option<&str>, board_revision: Option<&str>, bootloader_version: &str) -> Device {
+        Device {
+            hardware: hardware.map(str::to_owned),
+            board_revision: board_revision.map(str::to_owned),
+            bootloader_version: Some(bootloader_version.to_owned()),
+        }
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("2023.04"), Some(vec![2023, 4]));
+        assert_eq!(parse_version("U-Boot 2023.10-rc2 (Oct 02 2023 - 10:00:00 +0000)"), Some(vec![2023, 10]));
+        assert_eq!(parse_version("U-Boot"), None);
+        assert!(parse_version("2023.04") < parse_version("2023.10"));
+    }
+
+    #[test]
+    fn hardware_and_revision() {
+        let m = manifest(r#", "hardware": "dexter", "board_revisions": ["2", "3"]"#);
+        assert!(m.check_compatible(&device(Some("dexter"), Some("3"), "2023.04"), BANK_SIZE).is_ok());
+        assert!(m.check_compatible(&device(Some("other"), Some("3"), "2023.04"), BANK_SIZE).is_err());
+        assert!(m.check_compatible(&device(None, Some("3"), "2023.04"), BANK_SIZE).is_err());
+        assert!(m.check_compatible(&device(Some("dexter"), Some("1"), "2023.04"), BANK_SIZE).is_err());
+        assert!(m.check_compatible(&device(Some("dexter"), None, "2023.04"), BANK_SIZE).is_err());
+
+        // Without requirements in the manifest, anything goes
+        assert!(manifest("").check_compatible(&device(None, None, "2023.04"), BANK_SIZE).is_ok());
+    }
+
+    #[test]
+    fn min_bootloader_version() {
+        let m = manifest(r#", "min_bootloader_version": "2023.10""#);
+        assert!(m.check_compatible(&device(None, None, "U-Boot 2023.10"), BANK_SIZE).is_ok());
+        assert!(m.check_compatible(&device(None, None, "2024.01"), BANK_SIZE).is_ok());
+        assert!(m.check_compatible(&device(None, None, "2023.04"), BANK_SIZE).is_err());
+        assert!(m.check_compatible(&device(None, None, "unknown"), BANK_SIZE).is_err());
+
+        let invalid = manifest(r#", "min_bootloader_version": "latest""#);
+        assert!(invalid.check_compatible(&device(None, None, "2023.10"), BANK_SIZE).is_err());
+    }
+
+    #[test]
+    fn required_space() {
+        let m = manifest(&format!(r#", "required_space_bytes": {}"#, BANK_SIZE));
+        assert!(m.check_compatible(&device(None, None, "2023.04"), BANK_SIZE).is_ok());
+        assert!(m.check_compatible(&device(None, None, "2023.04"), BANK_SIZE - 1).is_err());
+    }
+}
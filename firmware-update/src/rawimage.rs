@@ -0,0 +1,108 @@
+//! Update by writing a raw ext4 filesystem image onto the other bank, instead of formatting it
+//! and extracting a tar archive file by file.
+//!
+//! The image is recognised by the ext4 superblock magic. Its first HEAD_SIZE bytes, which contain
+//! the superblock, are zeroed on the device before writing and only written once the whole image
+//! has been verified. Until then the bank contains no recognisable filesystem, so an interrupted
+//! or invalid image can never be mounted or booted.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+
+use crate::banks::{self, Bank};
+use crate::config::PartitionLayout;
+use crate::safety;
+use crate::{check_cancelled, ProgressPrinter};
+
+/// Bytes at the start of the image that are written last. The ext4 superblock is at 1024.
+pub const HEAD_SIZE : u64 = 64 * 1024;
+
+const SUPERBLOCK_OFFSET : usize = 1024;
+const MAGIC_OFFSET : usize = SUPERBLOCK_OFFSET + 0x38;
+const EXT4_MAGIC : [u8; 2] = [0x53, 0xEF];
+
+const CHUNK_SIZE : usize = 1024 * 1024;
+
+pub fn is_ext4(head: &[u8]) -> bool {
+    head.get(MAGIC_OFFSET..MAGIC_OFFSET + 2) == Some(&EXT4_MAGIC[..])
+}
+
+/// Write the image, except its head, to the other bank. Returns the bank that was written.
+pub fn write_other_bank(layout: &PartitionLayout, image: &mut dyn Read, printer: &mut ProgressPrinter, cancel_requested: &AtomicBool) -> Result<Bank, Box<dyn std::error::Error>> {
+    let other_bank = banks::detect(layout)?.other();
+    let device_path = &layout.bank(other_bank).device;
+    safety::check_safe_to_write(device_path)?;
+
+    let mut device = File::options().write(true).open(device_path)
+        .map_err(|e| format!("Cannot open {}: {}", device_path, e))?;
+    let device_size = device.seek(SeekFrom::End(0))?;
+
+    eprintln!("Writing raw image to {} ({} MB)", device_path, device_size / 1024 / 1024);
+
+    device.seek(SeekFrom::Start(0))?;
+    device.write_all(&[0; HEAD_SIZE as usize])?;
+    device.sync_all()?;
+
+    let mut position = HEAD_SIZE;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        check_cancelled(cancel_requested)?;
+
+        let n = image.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if position + n as u64 > device_size {
+            return Err(format!("Image is larger than {} ({} bytes)", device_path, device_size).into());
+        }
+
+        device.write_all(&buf[..n])?;
+        position += n as u64;
+        printer.tick(0);
+    }
+
+    device.sync_all()?;
+    eprintln!("{} MB written to {}", position / 1024 / 1024, device_path);
+
+    Ok(other_bank)
+}
+
+/// Once the image is verified, write its head to make the filesystem valid, then check it,
+/// grow it to the size of the partition and give it the label of the bank
+pub fn finish(layout: &PartitionLayout, bank: Bank, head: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let partition = layout.bank(bank);
+    safety::check_safe_to_write(&partition.device)?;
+
+    write_head(&partition.device, head)?;
+
+    // resize2fs requires a freshly checked filesystem. Exit code 1 means errors were corrected.
+    let r = run("e2fsck", &["-f", "-p", &partition.device], &[0, 1])
+        .and_then(|_| run("resize2fs", &[&partition.device], &[0]))
+        .and_then(|_| run("e2label", &[&partition.device, &partition.label], &[0]));
+
+    if r.is_err() {
+        // Do not leave a filesystem behind that we could not check
+        write_head(&partition.device, &[0; HEAD_SIZE as usize])?;
+    }
+    r
+}
+
+fn write_head(device_path: &str, head: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut device = File::options().write(true).open(device_path)?;
+    device.write_all(head)?;
+    device.sync_all()?;
+    Ok(())
+}
+
+fn run(program: &str, args: &[&str], ok_codes: &[i32]) -> Result<(), Box<dyn std::error::Error>> {
+    eprintln!("Running {} {}", program, args.join(" "));
+    let output = Command::new(program).args(args).output()
+        .map_err(|e| format!("Cannot run {}: {}", program, e))?;
+
+    match output.status.code() {
+        Some(code) if ok_codes.contains(&code) => Ok(()),
+        _ => Err(format!("{} failed with {}: {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim()).into()),
+    }
+}
@@ -0,0 +1,121 @@
+//! Last check before anything is written to a bank device.
+//!
+//! Formatting, writing a raw image and mounting the other bank rely on `banks::detect()` to
+//! pick the bank we do not run from. If the detection is wrong, for example because of a wrong
+//! fstab, these checks still refuse to touch the device that holds `/`, found both with stat()
+//! and from /proc/self/mountinfo, or a device that is mounted read-write anywhere. A whole disk
+//! counts as in use when one of its partitions is.
+
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+/// One line of /proc/self/mountinfo
+pub struct MountInfo {
+    /// major:minor of the mounted device
+    pub device_number : String,
+    pub mountpoint : String,
+    /// Often /dev/root for the root filesystem, so compare the device_number instead
+    pub source : String,
+    pub read_write : bool,
+}
+
+/// Refuse if the device holds the running root filesystem. Used before mounting the other bank.
+pub fn check_not_root(device: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let target = block_device_number(device)?;
+    let mounts = mountinfo()?;
+
+    let mut root_devices = vec![split_device_number(std::fs::metadata("/")?.dev())];
+    if let Some(root) = root_mount(&mounts) {
+        root_devices.push(root.device_number.clone());
+        // /dev/root has no device node on most systems
+        if let Ok(number) = block_device_number(&root.source) {
+            root_devices.push(number);
+        }
+    }
+
+    for root in root_devices {
+        if is_or_contains(&target, &root) {
+            return Err(format!("Refusing to use {}: it holds the running root filesystem ({})", device, root).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Refuse if the device holds the running root filesystem or is mounted read-write anywhere.
+/// Used before every operation that writes to the device directly.
+pub fn check_safe_to_write(device: &str) -> Result<(), Box<dyn std::error::Error>> {
+    check_not_root(device)?;
+
+    let target = block_device_number(device)?;
+    if let Some(mount) = mountinfo()?.iter().find(|m| m.read_write && is_or_contains(&target, &m.device_number)) {
+        return Err(format!("Refusing to write to {}: {} is mounted read-write on {}", device, mount.source, mount.mountpoint).into());
+    }
+
+    Ok(())
+}
+
+/// The visible mount on /, which is the last one
+pub fn root_mount(mounts: &[MountInfo]) -> Option<&MountInfo> {
+    mounts.iter().rfind(|m| m.mountpoint == "/")
+}
+
+/// The major:minor of a block device, as in /proc/self/mountinfo
+pub fn block_device_number(device: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let metadata = std::fs::metadata(device)
+        .map_err(|e| format!("Cannot check {}: {}", device, e))?;
+    if !metadata.file_type().is_block_device() {
+        return Err(format!("Refusing to use {}: not a block device", device).into());
+    }
+    Ok(split_device_number(metadata.rdev()))
+}
+
+/// Same encoding as the kernel's new_encode_dev()
+fn split_device_number(dev: u64) -> String {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    format!("{}:{}", major, minor)
+}
+
+/// Whether `device` is `target`, or a partition of the disk `target`
+pub fn is_or_contains(target: &str, device: &str) -> bool {
+    if target == device {
+        return true;
+    }
+
+    let sys_path = Path::new("/sys/dev/block").join(device);
+    if !sys_path.join("partition").exists() {
+        return false;
+    }
+    std::fs::canonicalize(&sys_path).ok()
+        .and_then(|p| std::fs::read_to_string(p.with_file_name("dev")).ok())
+        .is_some_and(|disk| disk.trim() == target)
+}
+
+/// The mounts in the order of /proc/self/mountinfo, where the last mount on a path is the visible one
+pub fn mountinfo() -> Result<Vec<MountInfo>, Box<dyn std::error::Error>> {
+    // `22 1 179:2 / / rw,noatime shared:1 - ext4 /dev/root rw`
+    // The optional fields before " - " vary in number.
+    let contents = std::fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|e| format!("Cannot read /proc/self/mountinfo: {}", e))?;
+
+    let mut mounts = Vec::new();
+    for line in contents.lines() {
+        let (before, after) = line.split_once(" - ").ok_or(format!("Invalid mountinfo line '{}'", line))?;
+        let fields : Vec<&str> = before.split_whitespace().collect();
+        let super_fields : Vec<&str> = after.split_whitespace().collect();
+        if fields.len() < 6 || super_fields.len() < 3 {
+            return Err(format!("Invalid mountinfo line '{}'", line).into());
+        }
+
+        let is_rw = |options: &str| options.split(',').any(|o| o == "rw");
+        mounts.push(MountInfo {
+            device_number: fields[2].to_owned(),
+            mountpoint: fields[4].replace("\\040", " "),
+            source: super_fields[1].to_owned(),
+            read_write: is_rw(fields[5]) || is_rw(super_fields[2]),
+        });
+    }
+
+    Ok(mounts)
+}
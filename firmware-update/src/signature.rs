@@ -0,0 +1,34 @@
+use std::path::Path;
+
+use minisign_verify::{PublicKey, Signature};
+
+/// Location of the minisign public key that firmware images must be signed with
+pub const PUBLIC_KEY_PATH : &str = "/etc/firmware-update/minisign.pub";
+
+/// The detached signature is expected next to the image, with the usual minisign suffix
+pub fn signature_url(image_url: &str) -> String {
+    format!("{}.minisig", image_url)
+}
+
+/// Load the public key installed on the device. Returns None if no key is installed and
+/// `allow_unsigned` is set, in which case images are not verified.
+pub fn load_public_key(allow_unsigned: bool) -> Result<Option<PublicKey>, Box<dyn std::error::Error>> {
+    if !Path::new(PUBLIC_KEY_PATH).exists() {
+        if allow_unsigned {
+            return Ok(None);
+        }
+        return Err(format!("No public key in {}, set allow_unsigned in [signature] to install unsigned images", PUBLIC_KEY_PATH).into());
+    }
+
+    match PublicKey::from_file(PUBLIC_KEY_PATH) {
+        Ok(pk) => Ok(Some(pk)),
+        Err(e) => Err(format!("Cannot load public key {}: {}", PUBLIC_KEY_PATH, e).into()),
+    }
+}
+
+pub fn decode_signature(signature: &str) -> Result<Signature, Box<dyn std::error::Error>> {
+    match Signature::decode(signature) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("Cannot decode signature: {}", e).into()),
+    }
+}
@@ -0,0 +1,234 @@
+//! Where the image is taken from.
+//!
+//! The `from_url` of an Update can be:
+//!  - an `http://` or `https://` URL, downloaded as described in download.rs
+//!  - a `file://` URL or an absolute path to a local file
+//!  - `removable:`, the first image found in the top directory of a USB stick or other
+//!    removable device. The device is mounted read-only if it is not mounted already.
+//!
+//! The manifest and signature are looked for next to the image in all cases.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use sys_mount::{Mount, MountFlags, Unmount, UnmountDrop, UnmountFlags};
+
+use crate::config::{Download, PartitionLayout};
+use crate::download::{self, ResumableReader};
+use crate::safety;
+use crate::{check_cancelled, with_credentials, Credentials};
+
+pub const REMOVABLE_SOURCE : &str = "removable:";
+const REMOVABLE_MOUNTPOINT : &str = "/mnt/removable";
+const IMAGE_EXTENSIONS : [&str; 10] = [
+    ".tar.zst", ".tar.zstd", ".tar.xz", ".tar.gz", ".tgz", ".tar",
+    ".ext4.zst", ".ext4.xz", ".ext4.gz", ".ext4",
+];
+
+/// The image stream and its size, if known
+pub type OpenedImage = (Box<dyn Read>, Option<u64>);
+
+pub enum Source {
+    Http {
+        url: String,
+        creds: Option<Credentials>,
+    },
+    File {
+        path: PathBuf,
+        /// Unmounts the removable device we mounted once the update is done
+        _mount: Option<UnmountDrop<Mount>>,
+    },
+}
+
+impl Source {
+    pub fn resolve(from_url: &str, creds: Option<Credentials>, layout: &PartitionLayout) -> Result<Source, Box<dyn std::error::Error>> {
+        if from_url.starts_with("http://") || from_url.starts_with("https://") {
+            return Ok(Source::Http { url: from_url.to_owned(), creds });
+        }
+
+        if creds.is_some() {
+            return Err("Username and password can only be used with HTTP".into());
+        }
+
+        if from_url == REMOVABLE_SOURCE {
+            return find_on_removable_device(layout);
+        }
+
+        let path = PathBuf::from(from_url.strip_prefix("file://").unwrap_or(from_url));
+        if !path.is_absolute() {
+            return Err(format!("Unsupported image location '{}'", from_url).into());
+        }
+        if !path.is_file() {
+            return Err(format!("Image {} does not exist", path.to_string_lossy()).into());
+        }
+
+        Ok(Source::File { path, _mount: None })
+    }
+
+    /// URL or path of the image
+    pub fn location(&self) -> String {
+        match self {
+            Source::Http { url, .. } => url.clone(),
+            Source::File { path, .. } => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Read a small file published next to the image, whose location is given by `sidecar`
+    /// from the location of the image. Returns None if there is no such file.
+    pub fn fetch_sidecar(&self, sidecar: fn(&str) -> String, settings: &Download, cancel_requested: &AtomicBool) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let location = sidecar(&self.location());
+
+        match self {
+            Source::Http { creds, .. } => {
+                check_cancelled(cancel_requested)?;
+                let request = with_credentials(download::agent(settings).get(&location), creds);
+                let r = match request.call() {
+                    Ok(response) => response.into_string()
+                        .map(Some)
+                        .map_err(|e| format!("Cannot fetch {}: {}", location, e).into()),
+                    Err(ureq::Error::Status(404, _)) => Ok(None),
+                    Err(e) => Err(format!("Cannot fetch {}: {}", location, e).into()),
+                };
+                // The timeouts bound the wait, the cancellation is noticed once it is over
+                check_cancelled(cancel_requested)?;
+                r
+            },
+            Source::File { .. } => {
+                let path = Path::new(&location);
+                if path.exists() {
+                    Ok(Some(std::fs::read_to_string(path)?))
+                }
+                else {
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    /// Open the image, and return it with its size if known
+    pub fn open(&self, settings: &Download, cancel_requested: Arc<AtomicBool>) -> Result<OpenedImage, Box<dyn std::error::Error>> {
+        match self {
+            Source::Http { url, creds } => {
+                let download = ResumableReader::open(url, creds.clone(), settings, cancel_requested)?;
+                let size = download.content_length();
+                Ok((Box::new(download), size))
+            },
+            Source::File { path, .. } => {
+                let file = File::open(path)
+                    .map_err(|e| format!("Cannot open {}: {}", path.to_string_lossy(), e))?;
+                let size = file.metadata()?.len();
+                Ok((Box::new(file), Some(size)))
+            },
+        }
+    }
+}
+
+fn is_image(name: &str) -> bool {
+    IMAGE_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+/// Partitions of the disks that are removable or connected over USB, or the disk itself if it
+/// has no partitions. Disks that hold our boot partition or banks are never considered.
+fn removable_devices(layout: &PartitionLayout) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    // Compared by device number, so that /dev/disk/by-* symlinks work and disks whose names
+    // share a prefix, like sda and sdaa, are told apart
+    let ours : Vec<String> = [&layout.boot.device, &layout.bank_a.device, &layout.bank_b.device].iter()
+        .filter_map(|device| safety::block_device_number(device).ok())
+        .collect();
+
+    let mut disks : Vec<String> = std::fs::read_dir("/sys/block")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    disks.sort();
+
+    let mut devices = Vec::new();
+    for disk in disks {
+        let sys_path = Path::new("/sys/block").join(&disk);
+
+        let removable = std::fs::read_to_string(sys_path.join("removable")).is_ok_and(|r| r.trim() == "1");
+        let usb = std::fs::canonicalize(&sys_path).is_ok_and(|p| p.to_string_lossy().contains("/usb"));
+        if !(removable || usb) {
+            continue;
+        }
+
+        let disk_number = std::fs::read_to_string(sys_path.join("dev"))?;
+        if ours.iter().any(|device| safety::is_or_contains(disk_number.trim(), device)) {
+            continue;
+        }
+
+        let mut partitions : Vec<PathBuf> = std::fs::read_dir(&sys_path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("partition").exists())
+            .map(|entry| Path::new("/dev").join(entry.file_name()))
+            .collect();
+        partitions.sort();
+
+        if partitions.is_empty() {
+            devices.push(Path::new("/dev").join(&disk));
+        }
+        else {
+            devices.append(&mut partitions);
+        }
+    }
+
+    Ok(devices)
+}
+
+/// Where the device is mounted already, from /proc/self/mounts
+fn existing_mountpoint(device: &Path) -> Option<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+    mounts.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.len() > 1 && Path::new(fields[0]) == device)
+        .map(|fields| PathBuf::from(fields[1].replace("\\040", " ")))
+}
+
+/// The first image in the top directory, in alphabetical order
+fn find_image(dir: &Path) -> Option<PathBuf> {
+    let mut images : Vec<PathBuf> = std::fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter(|entry| is_image(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .collect();
+    images.sort();
+    images.into_iter().next()
+}
+
+fn find_on_removable_device(layout: &PartitionLayout) -> Result<Source, Box<dyn std::error::Error>> {
+    let devices = removable_devices(layout)?;
+    if devices.is_empty() {
+        return Err("No removable device found".into());
+    }
+
+    for device in devices {
+        if let Some(mountpoint) = existing_mountpoint(&device) {
+            if let Some(path) = find_image(&mountpoint) {
+                eprintln!("Found image {} on {}", path.to_string_lossy(), device.to_string_lossy());
+                return Ok(Source::File { path, _mount: None });
+            }
+            continue;
+        }
+
+        std::fs::create_dir_all(REMOVABLE_MOUNTPOINT)?;
+        let mount = match Mount::builder().flags(MountFlags::RDONLY).mount(&device, REMOVABLE_MOUNTPOINT) {
+            Ok(mount) => mount.into_unmount_drop(UnmountFlags::DETACH),
+            Err(e) => {
+                eprintln!("Cannot mount {}: {}", device.to_string_lossy(), e);
+                continue;
+            },
+        };
+
+        if let Some(path) = find_image(Path::new(REMOVABLE_MOUNTPOINT)) {
+            eprintln!("Found image {} on {}", path.to_string_lossy(), device.to_string_lossy());
+            return Ok(Source::File { path, _mount: Some(mount) });
+        }
+        // Dropping the mount unmounts the device before trying the next one
+    }
+
+    Err(format!("No {} image found on the removable devices", IMAGE_EXTENSIONS.join(" or ")).into())
+}
@@ -1,7 +1,12 @@
-use std::{fs::File, io::Write, process::Command};
-use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
 
 use crate::banks::Bank;
+use crate::fw_env::Environment;
 
 pub enum UBootBankVariable {
     Desired,
@@ -21,50 +26,151 @@ impl UBootBankVariable {
 
 pub fn get_uboot_bank(bank_variable: UBootBankVariable) -> Result<Bank, Box<dyn std::error::Error>> {
     let var_name = bank_variable.env_var_name();
-    let out = Command::new("fw_printenv")
-        .arg(var_name)
-        .output()?;
+    let env = Environment::read_default()?;
 
-    if out.status.success() {
-        let stdout = String::from_utf8(out.stdout)?;
+    match env.get(var_name) {
+        Some("A") => Ok(Bank::A),
+        Some("B") => Ok(Bank::B),
+        Some(_) => Err(format!("{} is not A or B!", var_name).into()),
+        None => Err(format!("{} is not set", var_name).into()),
+    }
+}
 
-        let pattern = Regex::new(&format!(r"{}=([AB])", var_name)).unwrap();
+/// Counter incremented by U-Boot on every boot attempt while upgrade_available is 1.
+/// When it exceeds bootlimit, U-Boot runs altbootcmd instead of bootcmd.
+pub const BOOTCOUNT_VAR : &str = "bootcount";
+pub const BOOTLIMIT_VAR : &str = "bootlimit";
+pub const UPGRADE_AVAILABLE_VAR : &str = "upgrade_available";
 
-        if let Some(group) = pattern.captures(&stdout) {
-            let bank = &group[1];
-            Ok(bank.try_into()?)
-        }
-        else {
-            Err(format!("{} is not A or B!", var_name).into())
+/// Variables that select the bank to boot. They can only be changed through
+/// SetDesiredBank and SetBankOk, never through SetUBootEnv.
+pub const PROTECTED_VARS : [&str; 6] = [
+    "desired_bank",
+    "last_tried_bank",
+    "last_ok_bank",
+    BOOTCOUNT_VAR,
+    BOOTLIMIT_VAR,
+    UPGRADE_AVAILABLE_VAR,
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BootCounter {
+    pub bootcount : Option<u32>,
+    pub bootlimit : Option<u32>,
+    pub upgrade_available : bool,
+}
+
+pub fn get_boot_counter() -> Result<BootCounter, Box<dyn std::error::Error>> {
+    let env = Environment::read_default()?;
+    let number = |name| env.get(name).and_then(|v| v.parse().ok());
+
+    Ok(BootCounter {
+        bootcount: number(BOOTCOUNT_VAR),
+        bootlimit: number(BOOTLIMIT_VAR),
+        upgrade_available: env.get(UPGRADE_AVAILABLE_VAR) == Some("1"),
+    })
+}
+
+/// Select the bank to boot next. When it is not our bank, arm the boot counter so that
+/// U-Boot falls back if the new bank fails to boot `bootlimit` times.
+pub fn set_desired_bank(bank: Bank, our_bank: Bank, bootlimit: Option<u32>) -> Result<Changes, Box<dyn std::error::Error>> {
+    let mut transaction = Transaction::new()
+        .set_bank(UBootBankVariable::Desired, bank)
+        .set(BOOTCOUNT_VAR, "0");
+
+    if bank != our_bank {
+        // Let U-Boot record the attempt to boot the other bank
+        transaction = transaction
+            .unset(UBootBankVariable::LastTried.env_var_name())
+            .set(UPGRADE_AVAILABLE_VAR, "1");
+
+        if let Some(limit) = bootlimit {
+            transaction = transaction.set(BOOTLIMIT_VAR, &limit.to_string());
         }
     }
     else {
-        std::io::stderr().write_all(&out.stderr).unwrap();
-        Err("Failed to call fw_setenv!".into())
+        transaction = transaction.set(UPGRADE_AVAILABLE_VAR, "0");
     }
+
+    transaction.commit()
+}
+
+/// Record that our bank booted fine, and disarm the boot counter
+pub fn mark_bank_ok(bank: Bank) -> Result<Changes, Box<dyn std::error::Error>> {
+    Transaction::new()
+        .set_bank(UBootBankVariable::LastOk, bank)
+        .set(BOOTCOUNT_VAR, "0")
+        .set(UPGRADE_AVAILABLE_VAR, "0")
+        .commit()
+}
+
+pub fn get_uboot_env() -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    Ok(Environment::read_default()?.vars().clone())
 }
 
-pub fn set_uboot_bank(var_name: UBootBankVariable, bank: Bank) -> Result<(), Box<dyn std::error::Error>> {
-    let script = format!("{}={}", var_name.env_var_name(), bank);
-    let script_filename = "ubootfw.script";
+/// Taken by fw_setenv as well while it writes the environment
+const LOCK_PATH : &str = "/var/lock/fw_printenv.lock";
 
-    let mut file = File::options()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(script_filename)?;
-    file.write_all(script.as_bytes())?;
+/// Serialises the commits of the daemon loop and the boot confirmation thread
+static COMMIT_LOCK : Mutex<()> = Mutex::new(());
 
-    let out = Command::new("fw_setenv")
-        .arg("-s")
-        .arg(script_filename)
-        .output()?;
+/// Variables changed by a transaction, None for removed ones
+pub type Changes = BTreeMap<String, Option<String>>;
 
-    if out.status.success() {
-        Ok(())
+/// A set of changes to the U-Boot environment that are written together, in a single
+/// environment write. With a redundant environment, a power cut during the write leaves
+/// the previous environment in place, so either all or none of the changes are applied.
+pub struct Transaction {
+    changes : Vec<(String, Option<String>)>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction { changes: Vec::new() }
     }
-    else {
-        std::io::stderr().write_all(&out.stderr).unwrap();
-        Err("Failed to call fw_setenv!".into())
+
+    pub fn set(mut self, name: &str, value: &str) -> Self {
+        self.changes.push((name.to_owned(), Some(value.to_owned())));
+        self
     }
+
+    pub fn set_bank(self, var_name: UBootBankVariable, bank: Bank) -> Self {
+        self.set(var_name.env_var_name(), &bank.to_string())
+    }
+
+    pub fn unset(mut self, name: &str) -> Self {
+        self.changes.push((name.to_owned(), None));
+        self
+    }
+
+    pub fn commit(self) -> Result<Changes, Box<dyn std::error::Error>> {
+        // Without the locks, two commits could read the same environment, and the second
+        // write would drop the changes of the first
+        let _guard = COMMIT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let _lock_file = lock_environment()?;
+
+        let mut env = Environment::read_default()?;
+        for (name, value) in &self.changes {
+            match value {
+                Some(v) => env.set(name, v)?,
+                None => env.unset(name),
+            }
+        }
+        env.write()?;
+
+        Ok(self.changes.into_iter().collect())
+    }
+}
+
+/// Take the lock that fw_setenv uses. It is released when the returned file is closed.
+fn lock_environment() -> Result<File, Box<dyn std::error::Error>> {
+    if let Some(dir) = Path::new(LOCK_PATH).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let file = File::options().create(true).truncate(false).write(true).open(LOCK_PATH)
+        .map_err(|e| format!("Cannot open {}: {}", LOCK_PATH, e))?;
+    file.lock()
+        .map_err(|e| format!("Cannot lock {}: {}", LOCK_PATH, e))?;
+    Ok(file)
 }